@@ -0,0 +1,113 @@
+// Rust
+// Write-back cache of FAT sectors which doesn't exist in relan/exfat.
+
+#[derive(Debug)]
+pub(crate) struct FatCache {
+    sectors: std::collections::HashMap<u64, Vec<u8>>, // FAT relative sector
+    dirty: std::collections::BTreeSet<u64>,
+    capacity: usize, // in sectors
+}
+
+impl FatCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        assert_ne!(capacity, 0);
+        Self {
+            sectors: std::collections::HashMap::new(),
+            dirty: std::collections::BTreeSet::new(),
+            capacity,
+        }
+    }
+
+    pub(crate) fn is_full(&self) -> bool {
+        self.sectors.len() >= self.capacity
+    }
+
+    pub(crate) fn is_dirty(&self) -> bool {
+        !self.dirty.is_empty()
+    }
+
+    pub(crate) fn get(&self, sector: u64) -> Option<&[u8]> {
+        self.sectors.get(&sector).map(Vec::as_slice)
+    }
+
+    pub(crate) fn insert(&mut self, sector: u64, buf: Vec<u8>) {
+        assert!(!self.is_full(), "FAT cache is full");
+        assert!(
+            !self.dirty.contains(&sector),
+            "dirty sector {sector} replaced"
+        );
+        self.sectors.insert(sector, buf);
+    }
+
+    // Return false if the sector isn't cached.
+    pub(crate) fn update(&mut self, sector: u64, offset: usize, buf: &[u8]) -> bool {
+        let Some(v) = self.sectors.get_mut(&sector) else {
+            return false;
+        };
+        v[offset..offset + buf.len()].copy_from_slice(buf);
+        self.dirty.insert(sector);
+        true
+    }
+
+    // Dirty sectors merged into (first sector, data) runs in ascending order.
+    pub(crate) fn get_dirty_runs(&self) -> Vec<(u64, Vec<u8>)> {
+        let mut runs: Vec<(u64, u64, Vec<u8>)> = vec![];
+        for sector in &self.dirty {
+            let buf = &self.sectors[sector];
+            match runs.last_mut() {
+                Some(x) if x.0 + x.1 == *sector => {
+                    x.1 += 1;
+                    x.2.extend_from_slice(buf);
+                }
+                _ => runs.push((*sector, 1, buf.clone())),
+            }
+        }
+        runs.into_iter().map(|x| (x.0, x.2)).collect()
+    }
+
+    pub(crate) fn clear_dirty(&mut self) {
+        self.dirty.clear();
+    }
+
+    pub(crate) fn clear(&mut self) {
+        assert!(!self.is_dirty(), "dirty sectors dropped");
+        self.sectors.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_fat_cache() {
+        let mut c = super::FatCache::new(3);
+        assert!(!c.is_full());
+        assert!(c.get(0).is_none());
+        assert!(!c.update(0, 0, &[1]));
+
+        c.insert(5, vec![0; 4]);
+        c.insert(6, vec![0; 4]);
+        assert!(!c.is_full());
+        c.insert(9, vec![0; 4]);
+        assert!(c.is_full());
+        assert!(!c.is_dirty());
+        assert!(c.get_dirty_runs().is_empty());
+
+        assert!(c.update(9, 0, &[1, 2]));
+        assert!(c.update(6, 2, &[3, 4]));
+        assert!(c.update(5, 1, &[5]));
+        assert!(c.is_dirty());
+        assert_eq!(c.get(5), Some([0, 5, 0, 0].as_slice()));
+        assert_eq!(c.get(6), Some([0, 0, 3, 4].as_slice()));
+        assert_eq!(
+            c.get_dirty_runs(),
+            [(5, vec![0, 5, 0, 0, 0, 0, 3, 4]), (9, vec![1, 2, 0, 0])]
+        );
+
+        c.clear_dirty();
+        assert!(!c.is_dirty());
+        assert_eq!(c.get(9), Some([1, 2, 0, 0].as_slice())); // still cached
+        c.clear();
+        assert!(!c.is_full());
+        assert!(c.get(9).is_none());
+    }
+}
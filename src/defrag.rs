@@ -0,0 +1,282 @@
+// Rust
+// Online defragmentation which doesn't exist in relan/exfat.
+
+#[derive(Debug, Default)]
+pub struct Fragmentation {
+    pub files: u64,      // nodes with clusters, including directories
+    pub fragmented: u64, // nodes with more than one cluster run
+    pub fragments: u64,  // cluster runs of all nodes
+}
+
+impl Fragmentation {
+    // Percentage of fragments which contiguous layout wouldn't have.
+    #[must_use]
+    pub fn score(&self) -> u64 {
+        (100 * (self.fragments - self.files))
+            .checked_div(self.fragments)
+            .unwrap_or(0)
+    }
+
+    #[must_use]
+    pub fn get_average_fragments(&self) -> f64 {
+        if self.files == 0 {
+            return 0.0;
+        }
+        // fragments can't outnumber clusters
+        f64::from(u32::try_from(self.fragments).unwrap_or(u32::MAX))
+            / f64::from(u32::try_from(self.files).unwrap_or(u32::MAX))
+    }
+}
+
+impl crate::exfat::Exfat {
+    /// # Errors
+    pub fn get_fragments(&mut self, nid: crate::node::Nid) -> crate::Result<usize> {
+        Ok(self.get_cluster_runs(nid)?.len())
+    }
+
+    /// # Errors
+    /// # Panics
+    pub fn get_fragmentation(&mut self) -> crate::Result<Fragmentation> {
+        self.with_all_cached(|ef| {
+            let mut frag = Fragmentation::default();
+            for nid in ef.collect_nids() {
+                let n = u64::try_from(ef.get_fragments(nid)?).unwrap();
+                if n != 0 {
+                    frag.files += 1;
+                    frag.fragments += n;
+                    if n > 1 {
+                        frag.fragmented += 1;
+                    }
+                }
+            }
+            Ok(frag)
+        })
+    }
+
+    fn copy_clusters(
+        &mut self,
+        runs: &[(u32, u32)],
+        start_cluster: u32,
+        count: u32,
+    ) -> crate::Result<()> {
+        let mut buf = vec![0; usize::try_from(self.get_cluster_size()).unwrap()];
+        let mut new = start_cluster;
+        for (cluster, n) in runs {
+            for old in *cluster..*cluster + *n {
+                if new - start_cluster >= count {
+                    return Ok(());
+                }
+                if let Err(e) = self.dev.pread(&mut buf, self.c2o(old)) {
+                    log::error!("failed to read cluster {old:#x}");
+                    return Err(e.into());
+                }
+                if let Err(e) = self.dev.pwrite(&buf, self.c2o(new)) {
+                    log::error!("failed to write cluster {new:#x}");
+                    return Err(e.into());
+                }
+                new += 1;
+            }
+        }
+        Ok(())
+    }
+
+    // Relocate clusters of a node into a contiguous run. Return false if the
+    // node doesn't need it.
+    // The old clusters remain referenced until the new entry set is written
+    // and synced, so that a crash leaks clusters rather than loses data.
+    /// # Errors
+    /// # Panics
+    pub fn defrag(&mut self, nid: crate::node::Nid) -> crate::Result<bool> {
+        if self.ro != 0 {
+            return Err(nix::errno::Errno::EROFS.into());
+        }
+        let node = crate::exfat::get_node!(self, &nid);
+        // root directory start cluster is in the super block
+        if nid == crate::node::NID_ROOT || node.size == 0 || node.is_contiguous {
+            return Ok(false);
+        }
+        let valid_size = node.valid_size;
+        let runs = self.get_cluster_runs(nid)?;
+        if runs.len() == 1 {
+            // chain is already contiguous, only the flag is missing
+            let node = crate::exfat::get_node_mut!(self, &nid);
+            node.is_contiguous = true;
+            node.is_dirty = true;
+            self.flush_node(nid)?;
+            return Ok(true);
+        }
+        // moving a directory would change ino of entries in it
+        if let crate::option::InoMode::Entry = self.opt.ino {
+            if crate::exfat::get_node!(self, &nid).is_directory() {
+                return Ok(false);
+            }
+        }
+
+        let count = runs.iter().map(|x| x.1).sum::<u32>();
+        let start_cluster = match self.cmap.find_best_run(usize::try_from(count).unwrap()) {
+            Some((index, len)) if len >= usize::try_from(count).unwrap() => {
+                crate::fs::EXFAT_FIRST_DATA_CLUSTER + u32::try_from(index).unwrap()
+            }
+            _ => {
+                log::warn!(
+                    "no free run of {} clusters for '{}'",
+                    count,
+                    crate::exfat::get_node!(self, &nid).get_name()
+                );
+                return Err(nix::errno::Errno::ENOSPC.into());
+            }
+        };
+
+        // allocate the new run, then copy clusters up to valid size
+        for cluster in start_cluster..start_cluster + count {
+            self.cmap.set_cluster(cluster)?;
+        }
+        let result = self.flush().and_then(|()| {
+            self.copy_clusters(&runs, start_cluster, self.bytes2clusters(valid_size)?)?;
+            Ok(self.dev.fsync()?)
+        });
+        if let Err(e) = result {
+            for cluster in start_cluster..start_cluster + count {
+                self.cmap.clear_cluster(cluster)?;
+            }
+            self.flush()?;
+            return Err(e);
+        }
+
+        // switch the node to the new run
+        let node = crate::exfat::get_node_mut!(self, &nid);
+        node.start_cluster = start_cluster;
+        node.is_contiguous = true;
+        node.fptr_index = 0;
+        node.fptr_cluster = start_cluster;
+        node.extents.clear();
+        node.is_dirty = true;
+        if let Err(e) = self.flush_node(nid) {
+            // the old chain is still intact, switch back to it
+            let node = crate::exfat::get_node_mut!(self, &nid);
+            node.start_cluster = runs[0].0;
+            node.is_contiguous = false;
+            node.fptr_index = 0;
+            node.fptr_cluster = runs[0].0;
+            node.extents.clear();
+            node.is_dirty = true;
+            for cluster in start_cluster..start_cluster + count {
+                self.cmap.clear_cluster(cluster)?;
+            }
+            self.flush()?;
+            return Err(e);
+        }
+        self.dev.fsync()?;
+
+        // the old chain is no longer referenced, free it like shrink_file
+        for (cluster, n) in runs {
+            for c in cluster..cluster + n {
+                self.set_next_cluster(false, c, crate::fs::EXFAT_CLUSTER_FREE)?;
+                self.free_cluster(c)?;
+            }
+        }
+        self.flush()?;
+        log::info!(
+            "defragmented '{}' into {:#x}+{}",
+            crate::exfat::get_node!(self, &nid).get_name(),
+            start_cluster,
+            count
+        );
+        Ok(true)
+    }
+
+    // Defragment all nodes which have more than one cluster run or lack the
+    // contiguous flag. Nodes which don't fit into any free run are skipped.
+    // progress is called with the number of processed and total nodes.
+    // Return the number of defragmented nodes.
+    /// # Errors
+    pub fn defrag_all<F: FnMut(usize, usize)>(&mut self, mut progress: F) -> crate::Result<usize> {
+        if self.ro != 0 {
+            return Err(nix::errno::Errno::EROFS.into());
+        }
+        self.with_all_cached(|ef| {
+            let mut nids = vec![];
+            for nid in ef.collect_nids() {
+                let node = crate::exfat::get_node!(ef, &nid);
+                if nid != crate::node::NID_ROOT && node.size != 0 && !node.is_contiguous {
+                    nids.push(nid);
+                }
+            }
+            let mut defragmented = 0;
+            for (i, nid) in nids.iter().enumerate() {
+                match ef.defrag(*nid) {
+                    Ok(true) => defragmented += 1,
+                    Ok(false) | Err(crate::Error::Errno(nix::errno::Errno::ENOSPC)) => (),
+                    Err(e) => return Err(e),
+                }
+                progress(i + 1, nids.len());
+            }
+            Ok(defragmented)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_fragmentation_score() {
+        let mut frag = super::Fragmentation::default();
+        assert_eq!(frag.score(), 0);
+        assert!(frag.get_average_fragments().abs() < f64::EPSILON);
+        frag.files = 2;
+        frag.fragments = 2;
+        assert_eq!(frag.score(), 0);
+        frag.fragmented = 1;
+        frag.fragments = 4;
+        assert_eq!(frag.score(), 50);
+        assert!((frag.get_average_fragments() - 2.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_defrag() {
+        let img = crate::testimg::TestImage::new("defrag", 1024);
+        let mut ef = img.mount(&["--noatime"]);
+        let a = ef.mknod("/a").unwrap();
+        let b = ef.mknod("/b").unwrap();
+        for nid in [a, b] {
+            crate::exfat::get_node_mut!(ef, &nid).get();
+        }
+        crate::testimg::write_clusters(&mut ef, &[a, b], 3);
+        let data = ef.read_all(a).unwrap();
+        let old = ef.get_cluster_runs(a).unwrap();
+        assert_eq!(old.len(), 3);
+        let free = ef.get_free_clusters().unwrap();
+
+        assert!(ef.defrag(a).unwrap());
+        assert!(!ef.defrag(a).unwrap());
+        assert!(crate::exfat::get_node!(ef, &a).is_contiguous);
+        assert_eq!(ef.get_fragments(a).unwrap(), 1);
+        assert_eq!(ef.get_free_clusters().unwrap(), free);
+        assert_eq!(ef.read_all(a).unwrap(), data);
+
+        // the old chain is freed in FAT as well as in clusters bitmap
+        let fat = u64::from(ef.get_super_block().fat_sector_start) * ef.get_sector_size();
+        for (cluster, _) in old {
+            assert!(!ef
+                .is_cluster_allocated(usize::try_from(cluster - 2).unwrap())
+                .unwrap());
+            let buf = img.pread(4, fat + u64::from(cluster) * 4);
+            assert_eq!(
+                u32::from_le_bytes(buf.try_into().unwrap()),
+                crate::fs::EXFAT_CLUSTER_FREE
+            );
+        }
+        for nid in [a, b] {
+            ef.flush_node(nid).unwrap();
+            crate::exfat::get_node_mut!(ef, &nid).put();
+        }
+        ef.unmount().unwrap();
+
+        let mut ef = img.mount(&["--noatime"]);
+        let a = ef.lookup("/a").unwrap();
+        assert_eq!(ef.read_all(a).unwrap(), data);
+        assert!(ef.check_bitmap().unwrap().is_clean());
+        crate::exfat::get_node_mut!(ef, &a).put();
+        ef.unmount().unwrap();
+    }
+}
@@ -17,6 +17,16 @@ pub(crate) use get_node_mut;
 
 const NAME_MAX: usize = 255;
 
+// main boot region, followed by backup boot region of the same size
+const BOOT_REGION_SECTORS: u64 = 12;
+
+// FAT sectors cached until written back, 128 KiB with 512 bytes sectors
+const FAT_CACHE_SECTORS: usize = 256;
+
+// directory data read at once while caching a directory, rounded up to
+// whole clusters
+const DIR_READ_SIZE: u64 = 64 * 1024;
+
 // UTF-16 encodes code points up to U+FFFF as single 16-bit code units.
 // UTF-8 uses up to 3 bytes (i.e. 8-bit code units) to encode code points
 // up to U+FFFF. relan/exfat has +1 for NULL termination.
@@ -43,6 +53,7 @@ pub struct Stat {
     pub st_atime: u64,
     pub st_mtime: u64,
     pub st_ctime: u64,
+    pub st_gen: u32, // Rust
 }
 
 #[derive(Debug)]
@@ -57,6 +68,14 @@ pub struct StatFs {
     pub f_frsize: u32,
 }
 
+// Rust
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FallocMode {
+    Allocate,  // extend size without zeroing, valid size stays behind
+    KeepSize,  // exFAT can't have clusters beyond size, unsupported beyond it
+    ZeroRange, // zero the range, extend size if needed
+}
+
 #[derive(Debug)]
 pub struct Cursor {
     pnid: crate::node::Nid,
@@ -76,10 +95,14 @@ impl Cursor {
 
 #[derive(Debug, Default)]
 pub(crate) struct ClusterMap {
-    start_cluster: u32,
+    pub(crate) start_cluster: u32,
     pub(crate) count: u32,
+    pub(crate) size: u64, // in bytes
     pub(crate) chunk: libfs::bitmap::Bitmap,
-    dirty: bool,
+    sector_size: u64,
+    dirty: std::collections::BTreeSet<u64>, // Rust: sectors to write back
+    free: u32,                              // Rust: free clusters counter
+    index: crate::freemap::FreeMap,         // Rust: free runs of the chunk
 }
 
 impl ClusterMap {
@@ -88,6 +111,167 @@ impl ClusterMap {
             ..Default::default()
         }
     }
+
+    fn get_index(cluster: u32) -> usize {
+        (cluster - crate::fs::EXFAT_FIRST_DATA_CLUSTER)
+            .try_into()
+            .unwrap()
+    }
+
+    pub(crate) fn set_cluster(&mut self, cluster: u32) -> nix::Result<()> {
+        let index = Self::get_index(cluster);
+        if !self.chunk.is_set(index)? {
+            self.free -= 1;
+            self.index.remove(index.try_into().unwrap(), 1);
+        }
+        self.chunk.set(index)?;
+        self.mark_dirty(index);
+        Ok(())
+    }
+
+    pub(crate) fn clear_cluster(&mut self, cluster: u32) -> nix::Result<()> {
+        let index = Self::get_index(cluster);
+        if self.chunk.is_set(index)? {
+            self.free += 1;
+            self.index.insert(index.try_into().unwrap(), 1);
+        }
+        self.chunk.clear(index)?;
+        self.mark_dirty(index);
+        Ok(())
+    }
+
+    // Replace the whole chunk, e.g. with a rebuilt one.
+    pub(crate) fn set_chunk(&mut self, chunk: libfs::bitmap::Bitmap) {
+        self.chunk = chunk;
+        self.rebuild();
+        self.mark_all_dirty();
+    }
+
+    // Rebuild the counter and the index from the chunk.
+    fn rebuild(&mut self) {
+        self.free = self.count_free();
+        self.index.clear();
+        let mut index = 0;
+        while let Some((start, len)) = self.find_run(index, false) {
+            self.index
+                .insert(start.try_into().unwrap(), len.try_into().unwrap());
+            index = start + len;
+        }
+    }
+
+    // Return the first free cluster index at or after hint, wrapping around.
+    fn find_next_free(&self, hint: usize) -> Option<usize> {
+        let hint = u64::try_from(hint).unwrap();
+        let index = match self.index.find_next(hint) {
+            Some(v) => v,
+            None => self.index.find_next(0)?,
+        };
+        Some(index.try_into().unwrap())
+    }
+
+    // Scan the chunk, only used on mount and for cross-checking the counter.
+    fn count_free(&self) -> u32 {
+        let bytes = self.chunk.as_bytes();
+        let count = usize::try_from(self.count).unwrap();
+        let mut used = bytes[..count / 8]
+            .iter()
+            .map(|x| x.count_ones())
+            .sum::<u32>();
+        let rem = count % 8;
+        if rem != 0 {
+            used += (bytes[count / 8] & ((1 << rem) - 1)).count_ones();
+        }
+        self.count - used
+    }
+
+    // index is a bit index of the chunk
+    pub(crate) fn mark_dirty(&mut self, index: usize) {
+        assert_ne!(self.sector_size, 0);
+        self.dirty
+            .insert(u64::try_from(index / 8).unwrap() / self.sector_size);
+    }
+
+    pub(crate) fn mark_all_dirty(&mut self) {
+        assert_ne!(self.sector_size, 0);
+        let n = crate::util::div_round_up!(
+            u64::try_from(self.chunk.as_bytes().len()).unwrap(),
+            self.sector_size
+        );
+        self.dirty.extend(0..n);
+    }
+
+    pub(crate) fn is_dirty(&self) -> bool {
+        !self.dirty.is_empty()
+    }
+
+    // Dirty sectors merged into (offset, length) byte ranges of the chunk.
+    fn get_dirty_ranges(&self) -> Vec<(usize, usize)> {
+        let total = self.chunk.as_bytes().len();
+        let sector_size = usize::try_from(self.sector_size).unwrap();
+        let mut ranges: Vec<(usize, usize)> = vec![];
+        for sector in &self.dirty {
+            let beg = usize::try_from(*sector).unwrap() * sector_size;
+            if beg >= total {
+                break;
+            }
+            let len = std::cmp::min(sector_size, total - beg);
+            match ranges.last_mut() {
+                Some(x) if x.0 + x.1 == beg => x.1 += len,
+                _ => ranges.push((beg, len)),
+            }
+        }
+        ranges
+    }
+
+    // Number of free clusters starting at index, up to max.
+    fn get_run_length(&self, index: usize, max: usize) -> usize {
+        match self.index.get(index.try_into().unwrap()) {
+            Some((start, len)) => std::cmp::min(usize::try_from(start + len).unwrap() - index, max),
+            None => 0,
+        }
+    }
+
+    // Return (index, length) of the first run of used or free clusters at or
+    // after index.
+    pub(crate) fn find_run(&self, index: usize, used: bool) -> Option<(usize, usize)> {
+        let count = usize::try_from(self.count).unwrap();
+        let bytes = self.chunk.as_bytes();
+        let (skip, take) = if used { (0x00, 0xff) } else { (0xff, 0x00) };
+        let is_used = |i: usize| (bytes[i / 8] & (1 << (i % 8))) != 0;
+        let is_whole = |i: usize, byte: u8| {
+            let r = i % 8;
+            r == 0 && i + 8 <= count && bytes[i / 8] == byte
+        };
+
+        let mut i = index;
+        while i < count && is_used(i) != used {
+            i += if is_whole(i, skip) { 8 } else { 1 };
+        }
+        if i >= count {
+            return None;
+        }
+        let start = i;
+        while i < count && is_used(i) == used {
+            i += if is_whole(i, take) { 8 } else { 1 };
+        }
+        Some((start, i - start))
+    }
+
+    // Return (index, length) of the smallest free run of at least n clusters,
+    // or the largest free run if there is no such run.
+    pub(crate) fn find_best_run(&self, n: usize) -> Option<(usize, usize)> {
+        let (start, len) = self.index.find_best(n.try_into().unwrap())?;
+        Some((start.try_into().unwrap(), len.try_into().unwrap()))
+    }
+}
+
+// Rust
+// Window of directory data, so that caching a directory reads clusters
+// rather than single entries.
+#[derive(Debug, Default)]
+struct DirBuffer {
+    offset: u64,
+    data: Vec<u8>,
 }
 
 #[derive(Debug, Default)]
@@ -113,14 +297,21 @@ pub struct Exfat {
     pub(crate) dev: crate::device::Device,
     pub(crate) sb: crate::fs::ExfatSuperBlock,
     upcase: Vec<u16>,
+    pub(crate) upcase_start_cluster: u32,
+    pub(crate) upcase_size: u64, // in bytes
     pub(crate) cmap: ClusterMap,
     pub(crate) strlabel: String,
     zero_cluster: Vec<u8>,
     pub(crate) ro: isize,
-    pub(crate) errors: usize,       // global variable in relan/exfat
-    pub(crate) errors_fixed: usize, // global variable in relan/exfat
-    pub(crate) imap: NidMap,        // Rust
-    pub(crate) nmap: std::collections::HashMap<crate::node::Nid, crate::node::Node>, // Rust
+    pub(crate) errors: usize,              // global variable in relan/exfat
+    pub(crate) errors_fixed: usize,        // global variable in relan/exfat
+    pub(crate) imap: NidMap,               // Rust
+    pub(crate) nmap: crate::node::NodeMap, // Rust
+    pub(crate) skipped: Vec<crate::repair::SkippedEntry>, // Rust
+    fat_cache: crate::cache::FatCache,     // Rust
+    pub(crate) lru: crate::lru::NodeLru,   // Rust
+    pub(crate) evict_held: usize,          // Rust
+    pub(crate) inos: std::collections::HashMap<u64, crate::node::Nid>, // Rust
 }
 
 impl Drop for Exfat {
@@ -140,6 +331,8 @@ impl Exfat {
             dev,
             sb: crate::fs::ExfatSuperBlock::new(),
             upcase: vec![],
+            upcase_start_cluster: 0,
+            upcase_size: 0,
             cmap: ClusterMap::new(),
             strlabel: String::new(),
             zero_cluster: vec![],
@@ -147,7 +340,12 @@ impl Exfat {
             errors: 0,
             errors_fixed: 0,
             imap: NidMap::new(),
-            nmap: std::collections::HashMap::new(),
+            nmap: crate::node::NodeMap::default(),
+            skipped: vec![],
+            fat_cache: crate::cache::FatCache::new(FAT_CACHE_SECTORS),
+            lru: crate::lru::NodeLru::default(),
+            evict_held: 0,
+            inos: std::collections::HashMap::new(),
         }
     }
 
@@ -192,7 +390,7 @@ impl Exfat {
     }
 
     // Size in bytes to size in clusters (rounded upwards).
-    fn bytes2clusters(&self, bytes: u64) -> nix::Result<u32> {
+    pub(crate) fn bytes2clusters(&self, bytes: u64) -> nix::Result<u32> {
         match crate::util::div_round_up!(bytes, self.get_cluster_size()).try_into() {
             Ok(v) => Ok(v),
             Err(e) => {
@@ -217,38 +415,170 @@ impl Exfat {
         if get_node!(self, &nid).is_contiguous {
             return cluster + 1;
         }
-        let fat_offset = self.s2o(u32::from_le(self.sb.fat_sector_start).into())
-            + u64::from(cluster) * crate::fs::EXFAT_CLUSTER_SIZE_U64;
-        let next = match self
-            .dev
-            .preadx(crate::fs::EXFAT_CLUSTER_SIZE_U64, fat_offset)
-        {
-            Ok(v) => v,
-            Err(e) => {
-                log::error!("{e}");
-                return crate::fs::EXFAT_CLUSTER_BAD;
+        self.get_fat_entry(cluster)
+    }
+
+    // Rust
+    // FAT entry of a cluster regardless of the node it belongs to.
+    pub(crate) fn get_fat_entry(&mut self, cluster: u32) -> u32 {
+        let (sector, offset) = self.get_fat_position(cluster);
+        if let Err(e) = self.cache_fat_sector(sector) {
+            log::error!("{e}");
+            return crate::fs::EXFAT_CLUSTER_BAD;
+        }
+        let buf = self.fat_cache.get(sector).unwrap();
+        u32::from_le_bytes(
+            buf[offset..offset + crate::fs::EXFAT_CLUSTER_SIZE]
+                .try_into()
+                .unwrap(),
+        )
+    }
+
+    // Rust
+    // FAT relative sector and offset within the sector of a FAT entry.
+    fn get_fat_position(&self, cluster: u32) -> (u64, usize) {
+        let offset = u64::from(cluster) * crate::fs::EXFAT_CLUSTER_SIZE_U64;
+        (
+            offset >> self.sb.sector_bits,
+            (offset % self.get_sector_size()).try_into().unwrap(),
+        )
+    }
+
+    fn cache_fat_sector(&mut self, sector: u64) -> std::io::Result<()> {
+        if self.fat_cache.get(sector).is_some() {
+            return Ok(());
+        }
+        if self.fat_cache.is_full() {
+            self.flush_fat()?;
+            self.fat_cache.clear();
+        }
+        let offset = self.s2o(u64::from(u32::from_le(self.sb.fat_sector_start)) + sector);
+        let buf = self.dev.preadx(self.get_sector_size(), offset)?;
+        self.fat_cache.insert(sector, buf);
+        Ok(())
+    }
+
+    fn flush_fat(&mut self) -> std::io::Result<()> {
+        for (sector, buf) in self.fat_cache.get_dirty_runs() {
+            let offset = self.s2o(u64::from(u32::from_le(self.sb.fat_sector_start)) + sector);
+            if let Err(e) = self.dev.pwrite(&buf, offset) {
+                log::error!("failed to write FAT sectors at {sector}");
+                return Err(e);
             }
-        };
-        u32::from_le_bytes(next.try_into().unwrap())
+        }
+        self.fat_cache.clear_dirty();
+        Ok(())
     }
 
     fn advance_cluster(&mut self, nid: crate::node::Nid, count: u32) -> nix::Result<u32> {
         let node = get_node_mut!(self, &nid);
-        if node.fptr_index > count {
-            node.fptr_index = 0;
-            node.fptr_cluster = node.start_cluster;
-        }
-        for _ in node.fptr_index..count {
-            let node_fptr_cluster = self.next_cluster(nid, get_node!(self, &nid).fptr_cluster);
-            get_node_mut!(self, &nid).fptr_cluster = node_fptr_cluster;
-            if self.cluster_invalid(node_fptr_cluster) {
-                log::error!("invalid cluster {node_fptr_cluster:#x}");
-                return Err(nix::errno::Errno::EIO);
+        if count >= u32::from_le(self.sb.cluster_count) {
+            log::error!("'{}' cannot occupy {} clusters", node.get_name(), count + 1);
+            return Err(nix::errno::Errno::EIO);
+        }
+        // Rust
+        // Non-contiguous clusters are looked up in extent map of the node,
+        // which is extended from FAT on demand.
+        let cluster = if count == 0 {
+            node.start_cluster
+        } else if node.is_contiguous {
+            node.start_cluster.saturating_add(count)
+        } else if let Some(v) = node.extents.get(count) {
+            v
+        } else {
+            if node.extents.is_empty() {
+                node.extents.push(node.start_cluster);
+            }
+            let mut index = node.extents.len() - 1;
+            let mut cluster = node.extents.get(index).unwrap();
+            let mut detector = crate::util::CycleDetector::new(cluster);
+            while index < count {
+                cluster = self.next_cluster(nid, cluster);
+                index += 1;
+                if self.cluster_invalid(cluster) {
+                    log::error!("invalid cluster {cluster:#x}");
+                    return Err(nix::errno::Errno::EIO);
+                }
+                let node = get_node_mut!(self, &nid);
+                if detector.step(cluster) {
+                    log::error!(
+                        "cluster chain of '{}' loops at {:#x}",
+                        node.get_name(),
+                        cluster
+                    );
+                    node.extents.clear();
+                    return Err(nix::errno::Errno::EIO);
+                }
+                node.extents.push(cluster);
             }
+            cluster
+        };
+        if count != 0 && self.cluster_invalid(cluster) {
+            log::error!("invalid cluster {cluster:#x}");
+            return Err(nix::errno::Errno::EIO);
         }
         let node = get_node_mut!(self, &nid);
         node.fptr_index = count;
-        Ok(node.fptr_cluster)
+        node.fptr_cluster = cluster;
+        Ok(cluster)
+    }
+
+    // Collect clusters of a node as (first cluster, count) runs, honoring
+    // is_contiguous. The chain is walked no further than the node size.
+    pub(crate) fn get_cluster_runs(
+        &mut self,
+        nid: crate::node::Nid,
+    ) -> crate::Result<Vec<(u32, u32)>> {
+        let node = get_node!(self, &nid);
+        let mut runs: Vec<(u32, u32)> = vec![];
+        if node.start_cluster == crate::fs::EXFAT_CLUSTER_FREE {
+            return Ok(runs);
+        }
+        let clusters = self.bytes2clusters(node.size)?;
+        let mut cluster = node.start_cluster;
+        if node.is_contiguous {
+            if clusters == 0
+                || self.cluster_invalid(cluster)
+                || self.cluster_invalid(cluster + (clusters - 1))
+            {
+                log::error!(
+                    "'{}' has invalid contiguous clusters {:#x}+{}",
+                    node.get_name(),
+                    cluster,
+                    clusters
+                );
+                return Err(nix::errno::Errno::EIO.into());
+            }
+            runs.push((cluster, clusters));
+            return Ok(runs);
+        }
+        let mut detector = crate::util::CycleDetector::new(cluster);
+        for i in 0..clusters {
+            if self.cluster_invalid(cluster) {
+                log::error!(
+                    "invalid cluster {:#x} in '{}'",
+                    cluster,
+                    get_node!(self, &nid).get_name()
+                );
+                return Err(nix::errno::Errno::EIO.into());
+            }
+            if i != 0 && detector.step(cluster) {
+                log::error!(
+                    "cluster chain of '{}' loops at {:#x}",
+                    get_node!(self, &nid).get_name(),
+                    cluster
+                );
+                return Err(nix::errno::Errno::EIO.into());
+            }
+            match runs.last_mut() {
+                Some(v) if v.0 + v.1 == cluster => v.1 += 1,
+                _ => runs.push((cluster, 1)),
+            }
+            if i + 1 < clusters {
+                cluster = self.next_cluster(nid, cluster);
+            }
+        }
+        Ok(runs)
     }
 
     /// # Errors
@@ -268,19 +598,26 @@ impl Exfat {
     }
 
     /// # Errors
+    /// # Panics
     pub fn flush(&mut self) -> crate::Result<()> {
-        if self.cmap.dirty {
+        // Rust
+        // FAT goes first as it did when FAT was written through.
+        self.flush_fat()?;
+        if self.cmap.is_dirty() {
             let offset = self.c2o(self.cmap.start_cluster);
-            if let Err(e) = self.dev.pwrite(self.cmap.chunk.as_bytes(), offset) {
-                log::error!("failed to write clusters bitmap");
-                return Err(e.into());
+            for (beg, len) in self.cmap.get_dirty_ranges() {
+                let buf = &self.cmap.chunk.as_bytes()[beg..beg + len];
+                if let Err(e) = self.dev.pwrite(buf, offset + u64::try_from(beg).unwrap()) {
+                    log::error!("failed to write clusters bitmap");
+                    return Err(e.into());
+                }
             }
-            self.cmap.dirty = false;
+            self.cmap.dirty.clear();
         }
         Ok(())
     }
 
-    fn set_next_cluster(
+    pub(crate) fn set_next_cluster(
         &mut self,
         contiguous: bool,
         current: u32,
@@ -289,12 +626,14 @@ impl Exfat {
         if contiguous {
             return Ok(());
         }
-        let fat_offset = self.s2o(u32::from_le(self.sb.fat_sector_start).into())
-            + u64::from(current) * crate::fs::EXFAT_CLUSTER_SIZE_U64;
-        if let Err(e) = self.dev.pwrite(&next.to_le().to_ne_bytes(), fat_offset) {
+        let (sector, offset) = self.get_fat_position(current);
+        if let Err(e) = self.cache_fat_sector(sector) {
             log::error!("failed to write the next cluster {next:#x} after {current:#x}");
             return Err(e);
         }
+        assert!(self
+            .fat_cache
+            .update(sector, offset, &next.to_le().to_ne_bytes()));
         Ok(())
     }
 
@@ -308,36 +647,49 @@ impl Exfat {
                 hint = 0;
             }
         }
-        let cluster = match self.ffas_cluster(hint, self.cmap.count) {
-            Ok(v) => v,
-            Err(nix::errno::Errno::ENOSPC) => match self.ffas_cluster(0, hint) {
-                Ok(v) => v,
-                Err(nix::errno::Errno::ENOSPC) => {
-                    log::error!("no free space left for cluster");
-                    return Err(nix::errno::Errno::ENOSPC);
-                }
-                Err(e) => return Err(e),
-            },
-            Err(e) => return Err(e),
+        // Rust
+        // relan/exfat scans clusters bitmap from hint, then wraps around.
+        let Some(index) = self.cmap.find_next_free(hint.try_into().unwrap()) else {
+            log::error!("no free space left for cluster");
+            return Err(nix::errno::Errno::ENOSPC);
         };
-        self.cmap.dirty = true;
+        let cluster = crate::fs::EXFAT_FIRST_DATA_CLUSTER + u32::try_from(index).unwrap();
+        self.cmap.set_cluster(cluster)?;
         Ok(cluster)
     }
 
-    fn free_cluster(&mut self, cluster: u32) -> nix::Result<()> {
+    pub(crate) fn free_cluster(&mut self, cluster: u32) -> nix::Result<()> {
         assert!(
             cluster - crate::fs::EXFAT_FIRST_DATA_CLUSTER < self.cmap.count,
             "caller must check cluster validity ({:#x},{:#x})",
             cluster,
             self.cmap.count
         );
-        self.cmap.chunk.clear(
-            (cluster - crate::fs::EXFAT_FIRST_DATA_CLUSTER)
-                .try_into()
-                .unwrap(),
-        )?;
-        self.cmap.dirty = true;
-        Ok(())
+        self.cmap.clear_cluster(cluster)
+    }
+
+    // Rust
+    // Return a cluster to allocate count clusters from, and the number of
+    // free clusters there. hint is used if enough clusters are free there,
+    // otherwise the smallest free run which fits is picked, or the largest
+    // one if none fits.
+    fn find_free_run(&self, hint: u32, count: u32) -> (u32, u32) {
+        let count = usize::try_from(count).unwrap();
+        if hint >= crate::fs::EXFAT_FIRST_DATA_CLUSTER
+            && hint - crate::fs::EXFAT_FIRST_DATA_CLUSTER < self.cmap.count
+        {
+            let len = self.cmap.get_run_length(ClusterMap::get_index(hint), count);
+            if len == count {
+                return (hint, u32::try_from(len).unwrap());
+            }
+        }
+        match self.cmap.find_best_run(count) {
+            Some((index, len)) => (
+                crate::fs::EXFAT_FIRST_DATA_CLUSTER + u32::try_from(index).unwrap(),
+                u32::try_from(len).unwrap(),
+            ),
+            None => (hint, 0), // let allocation fail
+        }
     }
 
     fn make_noncontiguous(&mut self, first: u32, last: u32) -> std::io::Result<()> {
@@ -356,6 +708,7 @@ impl Exfat {
         assert_ne!(difference, 0, "zero difference passed");
         let mut previous;
         let mut allocated = 0;
+        let mut run = 0; // free clusters left after previous
         let node = get_node!(self, &nid);
 
         if node.start_cluster == crate::fs::EXFAT_CLUSTER_FREE {
@@ -365,23 +718,41 @@ impl Exfat {
                 node.fptr_index
             );
             // file does not have clusters (i.e. is empty), allocate the first one for it
-            previous = self.allocate_cluster(0)?;
+            let (hint, n) = self.find_free_run(0, difference);
+            previous = self.allocate_cluster(hint)?;
+            run = n.saturating_sub(1);
             let node = get_node_mut!(self, &nid);
             node.fptr_cluster = previous;
             node.start_cluster = node.fptr_cluster;
+            node.extents.clear();
             allocated = 1;
             // file consists of only one cluster, so it's contiguous
             node.is_contiguous = true;
         } else {
             // get the last cluster of the file
+            // (extent map remains valid, existing clusters don't move)
             previous = self.advance_cluster(nid, current - 1)?;
         }
 
         while allocated < difference {
-            let next = match self.allocate_cluster(previous + 1) {
+            let hint = if run == 0 {
+                let (hint, n) = self.find_free_run(previous + 1, difference - allocated);
+                run = n;
+                hint
+            } else {
+                previous + 1
+            };
+            run = run.saturating_sub(1);
+            let next = match self.allocate_cluster(hint) {
                 Ok(v) => v,
                 Err(e) => {
                     if allocated != 0 {
+                        // terminate the chain so that shrink can verify it
+                        self.set_next_cluster(
+                            get_node!(self, &nid).is_contiguous,
+                            previous,
+                            crate::fs::EXFAT_CLUSTER_END,
+                        )?;
                         if let Err(e) = self.shrink_file(nid, current + allocated, allocated) {
                             log::error!("{e}");
                         }
@@ -396,6 +767,7 @@ impl Exfat {
                 let node = get_node_mut!(self, &nid);
                 node.is_contiguous = false;
                 node.is_dirty = true;
+                node.extents.clear();
             }
             self.set_next_cluster(get_node!(self, &nid).is_contiguous, previous, next)?;
             previous = next;
@@ -426,11 +798,22 @@ impl Exfat {
             "file underflow ({current} < {difference})"
         );
 
+        // verify clusters to be freed before changing anything, so that a
+        // looping chain doesn't free clusters of this or another file
+        let last = if current > difference {
+            Some(self.advance_cluster(nid, current - difference - 1)?)
+        } else {
+            None
+        };
+        let first = match last {
+            Some(v) => self.next_cluster(nid, v),
+            None => get_node!(self, &nid).start_cluster,
+        };
+        self.verify_chain(nid, first, difference)?;
+
         // crop the file
-        let mut previous;
-        if current > difference {
-            let last = self.advance_cluster(nid, current - difference - 1)?;
-            previous = self.next_cluster(nid, last);
+        let mut previous = first;
+        if let Some(last) = last {
             self.set_next_cluster(
                 get_node!(self, &nid).is_contiguous,
                 last,
@@ -438,13 +821,13 @@ impl Exfat {
             )?;
         } else {
             let node = get_node_mut!(self, &nid);
-            previous = node.start_cluster;
             node.start_cluster = crate::fs::EXFAT_CLUSTER_FREE;
             node.is_dirty = true;
         }
         let node = get_node_mut!(self, &nid);
         node.fptr_index = 0;
         node.fptr_cluster = node.start_cluster;
+        node.extents.truncate(current - difference);
 
         // free remaining clusters
         let mut difference = difference;
@@ -477,6 +860,46 @@ impl Exfat {
         Ok(())
     }
 
+    // Verify that a chain starting at the first cluster consists of count
+    // valid clusters without a loop. A chain which doesn't end there is only
+    // logged, since only count clusters are freed, and the file must remain
+    // removable.
+    fn verify_chain(&mut self, nid: crate::node::Nid, first: u32, count: u32) -> crate::Result<()> {
+        let mut cluster = first;
+        let mut detector = crate::util::CycleDetector::new(cluster);
+        for i in 0..count {
+            if self.cluster_invalid(cluster) {
+                log::error!(
+                    "invalid cluster {:#x} in '{}'",
+                    cluster,
+                    get_node!(self, &nid).get_name()
+                );
+                return Err(nix::errno::Errno::EIO.into());
+            }
+            if i != 0 && detector.step(cluster) {
+                log::error!(
+                    "cluster chain of '{}' loops at {:#x}",
+                    get_node!(self, &nid).get_name(),
+                    cluster
+                );
+                return Err(nix::errno::Errno::EIO.into());
+            }
+            if i + 1 < count {
+                cluster = self.next_cluster(nid, cluster);
+            }
+        }
+        if !get_node!(self, &nid).is_contiguous
+            && self.next_cluster(nid, cluster) != crate::fs::EXFAT_CLUSTER_END
+        {
+            log::warn!(
+                "cluster chain of '{}' doesn't end at {:#x}",
+                get_node!(self, &nid).get_name(),
+                cluster
+            );
+        }
+        Ok(())
+    }
+
     fn erase_range(&mut self, nid: crate::node::Nid, begin: u64, end: u64) -> crate::Result<()> {
         if begin >= end {
             return Ok(());
@@ -501,12 +924,15 @@ impl Exfat {
         // erase whole clusters
         while cluster_boundary < end {
             cluster = self.next_cluster(nid, cluster);
-            // the cluster cannot be invalid because we have just allocated it
-            assert!(
-                !self.cluster_invalid(cluster),
-                "invalid cluster {cluster:#x} after allocation"
-            );
-            self.erase_raw(cluster_size, self.c2o(cluster))?;
+            // the cluster should not be invalid because we have just allocated it
+            if self.cluster_invalid(cluster) {
+                log::error!("invalid cluster {cluster:#x} after allocation");
+                return Err(nix::errno::Errno::EIO.into());
+            }
+            self.erase_raw(
+                std::cmp::min(cluster_size, end - cluster_boundary),
+                self.c2o(cluster),
+            )?;
             cluster_boundary += cluster_size;
         }
         Ok(())
@@ -542,21 +968,115 @@ impl Exfat {
 
         let node = get_node_mut!(self, &nid);
         node.update_mtime();
+        // Rust
+        if let Some(slots) = &mut node.slots {
+            let a = node.size / crate::fs::EXFAT_ENTRY_SIZE_U64;
+            let b = size / crate::fs::EXFAT_ENTRY_SIZE_U64;
+            if a < b {
+                slots.insert(a, b - a);
+            } else {
+                slots.truncate(b);
+            }
+        }
         node.size = size;
         node.is_dirty = true;
         Ok(())
     }
 
+    // Rust
+    // Data beyond valid size reads as zeroes, so extending size doesn't
+    // require writing anything but clusters chain.
     /// # Errors
     /// # Panics
-    pub fn get_free_clusters(&self) -> crate::Result<u32> {
-        let mut free_clusters = 0;
-        for i in 0..self.cmap.count.try_into().unwrap() {
-            if !self.cmap.chunk.is_set(i)? {
-                free_clusters += 1;
+    pub fn fallocate(
+        &mut self,
+        nid: crate::node::Nid,
+        offset: u64,
+        len: u64,
+        mode: FallocMode,
+    ) -> crate::Result<()> {
+        if self.ro != 0 {
+            return Err(nix::errno::Errno::EROFS.into());
+        }
+        let node = get_node!(self, &nid);
+        if node.is_directory() {
+            return Err(nix::errno::Errno::EISDIR.into());
+        }
+        if len == 0 {
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+        let Some(end) = offset.checked_add(len) else {
+            return Err(nix::errno::Errno::EFBIG.into());
+        };
+        let size = node.size;
+        let valid_size = node.valid_size;
+        match mode {
+            FallocMode::Allocate => {
+                if end > size {
+                    self.truncate(nid, end, false)?;
+                }
+            }
+            FallocMode::KeepSize => {
+                // clusters up to size are already allocated
+                if end > size {
+                    log::error!("unable to allocate clusters beyond size {size}");
+                    return Err(nix::errno::Errno::EOPNOTSUPP.into());
+                }
+            }
+            FallocMode::ZeroRange => {
+                // nothing to zero beyond valid size
+                let zero_end = std::cmp::min(end, valid_size);
+                if offset < zero_end {
+                    self.erase_range(nid, offset, zero_end)?;
+                    get_node_mut!(self, &nid).update_mtime();
+                }
+                if end > size {
+                    self.truncate(nid, end, false)?;
+                }
             }
         }
-        Ok(free_clusters)
+        Ok(())
+    }
+
+    // Rust
+    // Data between the old and the new valid size is zeroed, so that stale
+    // clusters contents don't become visible.
+    /// # Errors
+    /// # Panics
+    pub fn set_valid_size(&mut self, nid: crate::node::Nid, valid_size: u64) -> crate::Result<()> {
+        if self.ro != 0 {
+            return Err(nix::errno::Errno::EROFS.into());
+        }
+        let node = get_node!(self, &nid);
+        if node.is_directory() {
+            return Err(nix::errno::Errno::EISDIR.into());
+        }
+        if valid_size > node.size {
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+        if valid_size == node.valid_size {
+            return Ok(());
+        }
+        self.erase_range(nid, node.valid_size, valid_size)?;
+        let node = get_node_mut!(self, &nid);
+        node.valid_size = valid_size;
+        node.update_mtime();
+        Ok(())
+    }
+
+    /// # Errors
+    /// # Panics
+    pub fn get_free_clusters(&self) -> crate::Result<u32> {
+        // Rust
+        // relan/exfat scans clusters bitmap each time.
+        if self.opt.debug {
+            assert_eq!(self.cmap.free, self.cmap.count_free());
+            assert_eq!(
+                u64::from(self.cmap.free),
+                self.cmap.index.iter().map(|x| x.1).sum::<u64>()
+            );
+        }
+        Ok(self.cmap.free)
     }
 
     fn find_used_clusters(&self, a: &mut u32, b: &mut u32) -> nix::Result<bool> {
@@ -703,6 +1223,12 @@ impl Exfat {
         if size == 0 {
             return Ok(0);
         }
+        // Rust
+        // writing beyond valid size makes the gap visible
+        let valid_size = get_node!(self, &nid).valid_size;
+        if offset > valid_size {
+            self.erase_range(nid, valid_size, offset)?;
+        }
 
         let cluster_size = self.get_cluster_size();
         let mut cluster = self.advance_cluster(nid, (offset / cluster_size).try_into().unwrap())?;
@@ -739,7 +1265,7 @@ impl Exfat {
         Ok(size - remainder)
     }
 
-    fn read_entries(
+    pub(crate) fn read_entries(
         &mut self,
         dnid: crate::node::Nid,
         n: usize,
@@ -770,7 +1296,7 @@ impl Exfat {
         Err(nix::errno::Errno::EIO.into())
     }
 
-    fn write_entries(
+    pub(crate) fn write_entries(
         &mut self,
         dnid: crate::node::Nid,
         entries: &[crate::fs::ExfatEntry],
@@ -940,13 +1466,13 @@ impl Exfat {
         ret
     }
 
-    fn parse_file_entries(
+    pub(crate) fn parse_file_entries(
         &mut self,
         dnid: crate::node::Nid,
         entries: &[crate::fs::ExfatEntry],
         n: usize,
         offset: u64,
-        xname: Option<&str>,
+        xname: Option<&[u16]>,
     ) -> nix::Result<crate::node::Nid> {
         if !Self::check_entries(entries, n) {
             return Err(nix::errno::Errno::EIO);
@@ -982,15 +1508,15 @@ impl Exfat {
         node.entry_offset = offset;
         node.init_meta1(meta1);
         node.init_meta2(meta2);
-        node.init_name(&entries[2..], usize::from(mandatory_entries) - 2);
+        node.set_entries(entries[..n].into()); // Rust: also keeps the name
         if let Some(xname) = xname {
-            if node.get_name() == xname {
-                for cnid in &get_node!(self, &dnid).cnids {
-                    if get_node!(self, cnid).get_name() == xname {
-                        return Ok(*cnid);
+            if node.get_name16() == xname {
+                for cnid in self.get_name_candidates(dnid, xname) {
+                    if get_node!(self, &cnid).get_name16() == xname {
+                        return Ok(cnid);
                     }
                 }
-                log::error!("failed to find cnid for {xname}");
+                log::error!("failed to find cnid for {}", node.get_name());
                 return Err(nix::errno::Errno::ENOENT);
             }
         }
@@ -998,19 +1524,72 @@ impl Exfat {
         assert!(get_node!(self, &nid).is_valid());
 
         if !self.check_node(nid, crate::util::calc_checksum(entries, n), meta1) {
+            // Rust
+            // the entry set is skipped, otherwise the directory is detached
+            // as a whole
+            if self.opt.rescue {
+                self.nmap_detach(dnid, nid)?;
+            }
             return Err(nix::errno::Errno::EIO);
         }
         Ok(nid)
     }
 
+    // Rust
+    // Same as read_entries, but served from the directory data window which
+    // is refilled as needed.
+    fn read_buffered_entries(
+        &mut self,
+        dnid: crate::node::Nid,
+        dbuf: &mut DirBuffer,
+        n: usize,
+        offset: u64,
+    ) -> crate::Result<Vec<crate::fs::ExfatEntry>> {
+        assert_ne!(n, 0);
+        let buf_size = crate::fs::EXFAT_ENTRY_SIZE * n;
+        let end = offset + u64::try_from(buf_size).unwrap();
+        let data_end = dbuf.offset + u64::try_from(dbuf.data.len()).unwrap();
+        if offset < dbuf.offset || end > data_end {
+            let dir_size = get_node!(self, &dnid).size;
+            let cluster_size = self.get_cluster_size();
+            let beg = crate::util::round_down!(offset, cluster_size);
+            let size = std::cmp::min(
+                crate::util::round_up!(std::cmp::max(end - beg, DIR_READ_SIZE), cluster_size),
+                dir_size.saturating_sub(beg),
+            );
+            dbuf.offset = beg;
+            dbuf.data.resize(size.try_into().unwrap(), 0);
+            let size = self.pread(dnid, &mut dbuf.data, beg)?;
+            dbuf.data.truncate(size.try_into().unwrap());
+        }
+
+        let data_end = dbuf.offset + u64::try_from(dbuf.data.len()).unwrap();
+        if end > data_end {
+            if offset >= data_end {
+                return Err(nix::errno::Errno::ENOENT.into());
+            }
+            log::error!(
+                "read {} bytes instead of {buf_size} bytes",
+                data_end - offset
+            );
+            return Err(nix::errno::Errno::EIO.into());
+        }
+        let beg = usize::try_from(offset - dbuf.offset).unwrap();
+        Ok(dbuf.data[beg..beg + buf_size]
+            .chunks_exact(crate::fs::EXFAT_ENTRY_SIZE)
+            .map(|x| *libfs::cast::align_to::<crate::fs::ExfatEntry>(x))
+            .collect())
+    }
+
     fn parse_file_entry(
         &mut self,
         dnid: crate::node::Nid,
+        dbuf: &mut DirBuffer,
         offset: u64,
         n: usize,
-        xname: Option<&str>,
+        xname: Option<&[u16]>,
     ) -> crate::Result<(crate::node::Nid, u64)> {
-        let entries = self.read_entries(dnid, n, offset)?;
+        let entries = self.read_buffered_entries(dnid, dbuf, n, offset)?;
         Ok((
             self.parse_file_entries(dnid, &entries, n, offset, xname)?,
             offset + crate::fs::EXFAT_ENTRY_SIZE_U64 * u64::try_from(n).unwrap(),
@@ -1046,21 +1625,33 @@ impl Exfat {
     fn cachedir(
         &mut self,
         dnid: crate::node::Nid,
+        dbuf: &mut DirBuffer,
         offset: u64,
-        xname: Option<&str>,
+        xname: Option<&[u16]>,
     ) -> crate::Result<(crate::node::Nid, u64)> {
         let mut offset = offset;
-        loop {
-            let entry = &self.read_entries(dnid, 1, offset)?[0];
+        'entry_loop: loop {
+            let entry = &self.read_buffered_entries(dnid, dbuf, 1, offset)?[0];
             match entry.typ {
                 crate::fs::EXFAT_ENTRY_FILE => {
                     let meta1: &crate::fs::ExfatEntryMeta1 = bytemuck::cast_ref(entry);
-                    return self.parse_file_entry(
+                    match self.parse_file_entry(
                         dnid,
+                        dbuf,
                         offset,
                         usize::from(1 + meta1.continuations),
                         xname,
-                    );
+                    ) {
+                        Err(crate::Error::Errno(nix::errno::Errno::ENOENT)) => {
+                            return Err(nix::errno::Errno::ENOENT.into());
+                        }
+                        Err(e) if self.opt.rescue => {
+                            // Rust
+                            offset = self.skip_entry_set(dnid, offset, &e)?;
+                            continue;
+                        }
+                        v => return v,
+                    }
                 }
                 crate::fs::EXFAT_ENTRY_UPCASE => 'upcase_label: {
                     if !self.upcase.is_empty() {
@@ -1083,6 +1674,15 @@ impl Exfat {
                     }
                     log::error!("unknown entry type {:#x}", entry.typ);
                     if !self.ask_to_fix() {
+                        if self.opt.rescue {
+                            // Rust
+                            offset = self.skip_entry_set(
+                                dnid,
+                                offset,
+                                &nix::errno::Errno::ECANCELED.into(),
+                            )?;
+                            continue 'entry_loop;
+                        }
                         return Err(nix::errno::Errno::ECANCELED.into());
                     }
                     self.fix_unknown_entry(dnid, entry, offset)?;
@@ -1137,6 +1737,8 @@ impl Exfat {
             &upcase_comp,
             upcase_size_usize / std::mem::size_of::<u16>(),
         );
+        self.upcase_start_cluster = u32::from_le(upcase.start_cluster);
+        self.upcase_size = upcase_size;
         Ok(())
     }
 
@@ -1160,6 +1762,8 @@ impl Exfat {
             );
             return Err(nix::errno::Errno::EIO.into());
         }
+        self.cmap.size = u64::from_le(bitmap.size);
+        self.cmap.sector_size = self.get_sector_size();
 
         let buf_size = crate::util::round_up!(
             u64::from(self.cmap.count),
@@ -1176,7 +1780,9 @@ impl Exfat {
                 return Err(e.into());
             }
         };
-        Ok(self.cmap.chunk.set_bytes(&buf)?)
+        self.cmap.chunk.set_bytes(&buf)?;
+        self.cmap.rebuild();
+        Ok(())
     }
 
     fn cachedir_entry_label(&mut self, label: &crate::fs::ExfatEntryLabel) -> nix::Result<()> {
@@ -1193,17 +1799,24 @@ impl Exfat {
         Ok(())
     }
 
-    fn cache_directory(&mut self, dnid: crate::node::Nid) -> crate::Result<()> {
+    pub(crate) fn cache_directory(&mut self, dnid: crate::node::Nid) -> crate::Result<()> {
+        // Rust
+        if self.opt.maxmem != 0 && dnid != crate::node::NID_ROOT {
+            let stamp = get_node!(self, &dnid).lru;
+            get_node_mut!(self, &dnid).lru = self.lru.touch(dnid, stamp);
+        }
         if get_node!(self, &dnid).is_cached {
             return Ok(()); // already cached
         }
-        self.cache_directory_impl(dnid, None)
+        self.cache_directory_impl(dnid, None)?;
+        // Rust
+        Ok(self.evict_nodes_if_needed(dnid)?)
     }
 
     pub(crate) fn recache_directory(
         &mut self,
         dnid: crate::node::Nid,
-        xname: &str,
+        xname: &[u16],
     ) -> crate::Result<()> {
         self.cache_directory_impl(dnid, Some(xname))
     }
@@ -1211,12 +1824,13 @@ impl Exfat {
     fn cache_directory_impl(
         &mut self,
         dnid: crate::node::Nid,
-        xname: Option<&str>,
+        xname: Option<&[u16]>,
     ) -> crate::Result<()> {
         let mut nids = vec![];
+        let mut dbuf = DirBuffer::default();
         let mut offset = 0;
         loop {
-            let (nid, next) = match self.cachedir(dnid, offset, xname) {
+            let (nid, next) = match self.cachedir(dnid, &mut dbuf, offset, xname) {
                 Ok(v) => v,
                 Err(e) => {
                     if let crate::Error::Errno(e) = e {
@@ -1248,23 +1862,106 @@ impl Exfat {
     ) -> nix::Result<crate::node::Nid> {
         assert_eq!(node.nid, crate::node::NID_NONE);
         node.nid = self.alloc_nid()?;
-        Ok(self.nmap_attach_node(dnid, node))
+        let nid = node.nid;
+        match self.nmap_attach_node(dnid, node) {
+            Ok(v) => Ok(v),
+            Err(e) => {
+                self.free_nid(nid)?;
+                Err(e)
+            }
+        }
+    }
+
+    // Rust
+    // Inode number of the entry set at offset in dnid, which is its device
+    // offset in entries, so that it doesn't depend on nid allocation.
+    fn get_entry_ino(&mut self, dnid: crate::node::Nid, offset: u64) -> nix::Result<u64> {
+        let cluster_size = self.get_cluster_size();
+        let cluster = self.advance_cluster(dnid, (offset / cluster_size).try_into().unwrap())?;
+        if self.cluster_invalid(cluster) {
+            log::error!("invalid cluster {cluster:#x} of entry at {offset}");
+            return Err(nix::errno::Errno::EIO);
+        }
+        Ok((self.c2o(cluster) + offset % cluster_size) / crate::fs::EXFAT_ENTRY_SIZE_U64)
+    }
+
+    // Rust
+    // Same as get_entry_ino, but the inode number must not be in use.
+    fn alloc_entry_ino(&mut self, dnid: crate::node::Nid, offset: u64) -> nix::Result<u64> {
+        let ino = self.get_entry_ino(dnid, offset)?;
+        if let Some(x) = self.inos.get(&ino) {
+            log::error!("ino {ino:#x} already used by nid {x}");
+            return Err(nix::errno::Errno::EEXIST);
+        }
+        Ok(ino)
+    }
+
+    // Rust
+    // Check the entry set of an ino which isn't cached, by reading it at
+    // its device offset. The stream entry is in the next cluster of the
+    // directory if the file entry is the last one in its cluster, and the
+    // generation is left to be checked once cached.
+    pub(crate) fn check_entry_ino(&mut self, ino: u64, gen: u32) -> crate::Result<()> {
+        let Some(offset) = ino.checked_mul(crate::fs::EXFAT_ENTRY_SIZE_U64) else {
+            return Err(nix::errno::Errno::ENOENT.into());
+        };
+        let cluster = self.s2c(offset >> self.sb.sector_bits)?;
+        if self.cluster_invalid(cluster) {
+            return Err(nix::errno::Errno::ENOENT.into());
+        }
+        let end = self.c2o(cluster) + self.get_cluster_size();
+        let n = std::cmp::min(2, (end - offset) / crate::fs::EXFAT_ENTRY_SIZE_U64);
+        let buf = self
+            .dev
+            .preadx(n * crate::fs::EXFAT_ENTRY_SIZE_U64, offset)?;
+        let entries: &[crate::fs::ExfatEntry] = bytemuck::cast_slice(&buf);
+        if entries[0].typ != crate::fs::EXFAT_ENTRY_FILE {
+            return Err(nix::errno::Errno::ENOENT.into());
+        }
+        if n == 2 {
+            if entries[1].typ != crate::fs::EXFAT_ENTRY_FILE_INFO {
+                return Err(nix::errno::Errno::ENOENT.into());
+            }
+            if crate::node::Node::calc_generation(entries) != gen {
+                return Err(nix::errno::Errno::ESTALE.into());
+            }
+        }
+        Ok(())
     }
 
     fn nmap_attach_node(
         &mut self,
         dnid: crate::node::Nid,
         mut node: crate::node::Node,
-    ) -> crate::node::Nid {
+    ) -> nix::Result<crate::node::Nid> {
         assert_ne!(dnid, crate::node::NID_NONE);
         assert_ne!(node.nid, crate::node::NID_NONE);
         assert_ne!(node.nid, crate::node::NID_ROOT); // root directly uses nmap
+
+        // Rust
+        // ino is already set if the caller has allocated it
+        if let crate::option::InoMode::Entry = self.opt.ino {
+            if node.ino == 0 {
+                node.ino = self.alloc_entry_ino(dnid, node.entry_offset)?;
+            }
+            self.inos.insert(node.ino, node.nid);
+        }
+        let hash = self.get_indexed_name_hash(dnid, &node.get_name16());
         let dnode = get_node_mut!(self, &dnid);
         node.pnid = dnode.nid;
         dnode.cnids.push(node.nid);
+        if let Some(hash) = hash {
+            dnode.names.insert(hash, node.nid);
+        }
+        if let Some(slots) = &mut dnode.slots {
+            slots.remove(
+                node.entry_offset / crate::fs::EXFAT_ENTRY_SIZE_U64,
+                1 + u64::from(node.continuations),
+            );
+        }
         let nid = node.nid;
         assert!(self.nmap.insert(node.nid, node).is_none());
-        nid
+        Ok(nid)
     }
 
     pub(crate) fn nmap_detach(
@@ -1274,6 +1971,11 @@ impl Exfat {
     ) -> nix::Result<crate::node::Node> {
         let node = self.nmap_detach_node(dnid, nid)?;
         self.free_nid(nid)?;
+        // Rust
+        // nid may be reused by another directory
+        if node.is_directory() {
+            self.skipped.retain(|x| x.dnid != nid);
+        }
         Ok(node)
     }
 
@@ -1292,12 +1994,61 @@ impl Exfat {
                 return Err(nix::errno::Errno::ENOENT);
             };
             node.pnid = crate::node::NID_NONE; // sanity
+            if node.ino != 0 {
+                assert_eq!(self.inos.remove(&node.ino), Some(nid));
+                node.ino = 0;
+            }
+            if let Some(hash) = self.get_indexed_name_hash(dnid, &node.get_name16()) {
+                get_node_mut!(self, &dnid).names.remove(hash, nid);
+            }
+            if let Some(slots) = &mut get_node_mut!(self, &dnid).slots {
+                slots.insert(
+                    node.entry_offset / crate::fs::EXFAT_ENTRY_SIZE_U64,
+                    1 + u64::from(node.continuations),
+                );
+            }
             Ok(node)
         } else {
             Err(nix::errno::Errno::ENOENT)
         }
     }
 
+    // Rust
+    fn get_name_hash(&self, name: &[u16]) -> u16 {
+        crate::util::calc_name_hash(&self.upcase, name, crate::utf::utf16_length(name))
+    }
+
+    // Rust
+    // Name hash to maintain name index of the directory, if it's built.
+    fn get_indexed_name_hash(&self, dnid: crate::node::Nid, name: &[u16]) -> Option<u16> {
+        if get_node!(self, &dnid).names.is_built() {
+            Some(self.get_name_hash(name))
+        } else {
+            None
+        }
+    }
+
+    // Rust
+    // Return children of the directory whose name hash matches name.
+    fn get_name_candidates(
+        &mut self,
+        dnid: crate::node::Nid,
+        name: &[u16],
+    ) -> Vec<crate::node::Nid> {
+        if !get_node!(self, &dnid).names.is_built() {
+            let v = get_node!(self, &dnid)
+                .cnids
+                .iter()
+                .map(|x| (self.get_name_hash(&get_node!(self, x).get_name16()), *x))
+                .collect::<Vec<_>>();
+            get_node_mut!(self, &dnid).names.build(&v);
+        }
+        get_node!(self, &dnid)
+            .names
+            .get(self.get_name_hash(name))
+            .to_vec()
+    }
+
     fn reset_node(&mut self) -> nix::Result<()> {
         self.reset_node_impl(crate::node::NID_ROOT)
     }
@@ -1340,17 +2091,11 @@ impl Exfat {
             return Ok(()); // do not flush unlinked node
         }
 
-        let mut entries = self.read_entries(
-            node.pnid,
-            (1 + node.continuations).into(),
-            node.entry_offset,
-        )?;
-        let node = get_node!(self, &nid);
-        if !Self::check_entries(&entries, (1 + node.continuations).into()) {
-            return Err(nix::errno::Errno::EIO.into());
-        }
+        // Rust
+        // relan/exfat reads entries back from the parent directory
+        let mut entries = node.entries.to_vec();
+        assert_eq!(entries.len(), (1 + node.continuations).into());
 
-        let node = get_node!(self, &nid);
         let meta1: &mut crate::fs::ExfatEntryMeta1 = bytemuck::cast_mut(&mut entries[0]);
         meta1.attrib = node.attrib.to_le();
         let (date, time, centisec, tzoffset) = crate::time::unix2exfat(node.mtime);
@@ -1377,37 +2122,38 @@ impl Exfat {
         let checksum = crate::util::calc_checksum(&entries, (1 + node.continuations).into());
         let meta1: &mut crate::fs::ExfatEntryMeta1 = bytemuck::cast_mut(&mut entries[0]);
         meta1.checksum = checksum;
-        self.write_entries(
-            node.pnid,
-            &entries,
-            (1 + node.continuations).into(),
-            node.entry_offset,
-        )?;
-        get_node_mut!(self, &nid).is_dirty = false;
-        self.flush()
+        // Rust
+        // FAT and clusters bitmap are written back first, so that the entry
+        // set never points at a chain which isn't on disk yet.
+        let (pnid, offset) = (node.pnid, node.entry_offset);
+        self.flush()?;
+        self.write_entries(pnid, &entries, entries.len(), offset)?;
+        let node = get_node_mut!(self, &nid);
+        node.set_entries(entries.into());
+        node.is_dirty = false;
+        Ok(())
     }
 
     fn erase_entries(
         &mut self,
         dnid: crate::node::Nid,
-        n: usize,
+        entries: &[crate::fs::ExfatEntry],
         offset: u64,
     ) -> crate::Result<()> {
-        let mut entries = self.read_entries(dnid, n, offset)?;
+        let mut entries = entries.to_vec();
         for entry in &mut entries {
             entry.typ &= !crate::fs::EXFAT_ENTRY_VALID;
         }
-        self.write_entries(dnid, &entries, n, offset)
+        self.write_entries(dnid, &entries, entries.len(), offset)
     }
 
     fn erase_node(&mut self, nid: crate::node::Nid) -> crate::Result<()> {
         let node = get_node!(self, &nid);
         let dnid = node.pnid;
-        let node_continuations = node.continuations;
+        let node_entries = node.entries.clone();
         let node_entry_offset = node.entry_offset;
         get_node_mut!(self, &dnid).get();
-        if let Err(e) = self.erase_entries(dnid, (1 + node_continuations).into(), node_entry_offset)
-        {
+        if let Err(e) = self.erase_entries(dnid, &node_entries, node_entry_offset) {
             get_node_mut!(self, &dnid).put();
             return Err(e);
         }
@@ -1450,7 +2196,7 @@ impl Exfat {
             entries += 2;
             // subentries with file name
             entries += u64::try_from(crate::util::div_round_up!(
-                crate::utf::utf16_length(&last_node.name),
+                crate::utf::utf16_length(&last_node.get_name16()),
                 crate::fs::EXFAT_ENAME_MAX
             ))
             .unwrap();
@@ -1558,71 +2304,94 @@ impl Exfat {
         Ok(())
     }
 
+    // Rust
+    // Free entries of the directory are indexed on the first use, and then
+    // maintained as nodes are attached or detached and as the directory
+    // grows or shrinks.
+    fn build_slots(&mut self, dnid: crate::node::Nid) {
+        let dnode = get_node!(self, &dnid);
+        let nentries = dnode.size / crate::fs::EXFAT_ENTRY_SIZE_U64;
+        let mut used = dnode
+            .cnids
+            .iter()
+            .map(|x| {
+                let node = get_node!(self, x);
+                let i = node.entry_offset / crate::fs::EXFAT_ENTRY_SIZE_U64;
+                (i, i + 1 + u64::from(node.continuations))
+            })
+            .collect::<Vec<_>>();
+        // entry sets skipped by --rescue aren't free either
+        used.extend(self.skipped.iter().filter(|x| x.dnid == dnid).map(|x| {
+            let i = x.offset / crate::fs::EXFAT_ENTRY_SIZE_U64;
+            (i, i + u64::try_from(x.entries).unwrap())
+        }));
+        used.sort_unstable();
+        used.push((nentries, nentries));
+
+        let mut slots = crate::freemap::FreeMap::default();
+        let mut i = 0;
+        for (beg, end) in used {
+            let beg = std::cmp::min(beg, nentries);
+            if i < beg {
+                slots.insert(i, beg - i);
+            }
+            i = std::cmp::max(i, end);
+        }
+        get_node_mut!(self, &dnid).slots = Some(Box::new(slots));
+    }
+
     fn find_slot(&mut self, dnid: crate::node::Nid, n: usize) -> crate::Result<u64> {
         let dnode = get_node!(self, &dnid);
         assert!(dnode.is_cached, "directory is not cached");
 
-        // build a bitmap of valid entries in the directory
-        // relan/exfat: why calloc(..., sizeof(bitmap_t)) ?
-        let nentries = usize::try_from(dnode.size).unwrap() / crate::fs::EXFAT_ENTRY_SIZE;
-        let mut dmap = libfs::bitmap::Bitmap::new(nentries)?;
-        for cnid in &dnode.cnids {
-            let node = get_node!(self, cnid);
-            for i in 0..=node.continuations {
-                dmap.set(
-                    usize::try_from(node.entry_offset).unwrap() / crate::fs::EXFAT_ENTRY_SIZE
-                        + usize::from(i),
-                )?;
-            }
+        // relan/exfat builds a bitmap of valid entries in the directory
+        // each time
+        if dnode.slots.is_none() {
+            self.build_slots(dnid);
         }
-
-        // find a slot in the directory entries bitmap
-        let mut offset = 0;
-        let mut contiguous = 0;
-        let mut i = 0;
-        while i < nentries {
-            if dmap.is_set(i)? {
-                contiguous = 0;
-            } else {
-                if contiguous == 0 {
-                    offset = u64::try_from(i).unwrap() * crate::fs::EXFAT_ENTRY_SIZE_U64;
-                }
-                contiguous += 1;
-                if contiguous == n {
-                    // suitable slot is found, check that it's not occupied
-                    match self.check_slot(dnid, offset, n) {
-                        Ok(()) => return Ok(offset), // slot is free
-                        Err(e) => match e {
-                            crate::Error::Errno(e) => match e {
-                                nix::errno::Errno::EINVAL => {
-                                    // slot at (i-n) is occupied, go back and check (i-n+1)
-                                    i -= contiguous - 1;
-                                    contiguous = 0;
-                                }
-                                _ => return Err(e.into()),
-                            },
-                            crate::Error::Error(e) => return Err(e.into()),
-                        },
+        let n64 = u64::try_from(n).unwrap();
+        // first fit like relan/exfat
+        loop {
+            let slots = get_node!(self, &dnid).slots.as_ref().unwrap();
+            let Some((start, _)) = slots.find_first(n64) else {
+                break;
+            };
+            // suitable slot is found, check that it's not occupied
+            let offset = start * crate::fs::EXFAT_ENTRY_SIZE_U64;
+            match self.check_slot(dnid, offset, n) {
+                Ok(()) => return Ok(offset), // slot is free
+                Err(crate::Error::Errno(nix::errno::Errno::EINVAL)) => {
+                    // occupied by entries without nodes, exclude them
+                    let entries = self.read_entries(dnid, n, offset)?;
+                    let slots = get_node_mut!(self, &dnid).slots.as_mut().unwrap();
+                    for (i, entry) in entries.iter().enumerate() {
+                        if (entry.typ & crate::fs::EXFAT_ENTRY_VALID) != 0 {
+                            slots.remove(start + u64::try_from(i).unwrap(), 1);
+                        }
                     }
                 }
+                Err(e) => return Err(e),
             }
-            i += 1;
         }
 
         // no suitable slots found, extend the directory
+        // reusing free entries at the end of it
         let dir_size = get_node!(self, &dnid).size;
-        if contiguous == 0 {
-            offset = dir_size;
-        }
+        let nentries = dir_size / crate::fs::EXFAT_ENTRY_SIZE_U64;
+        let slots = get_node!(self, &dnid).slots.as_ref().unwrap();
+        let contiguous = match nentries.checked_sub(1).and_then(|x| slots.get(x)) {
+            Some((start, len)) if start + len == nentries => len,
+            _ => 0,
+        };
         self.truncate(
             dnid,
             crate::util::round_up!(
-                dir_size + crate::fs::EXFAT_ENTRY_SIZE_U64 * u64::try_from(n - contiguous).unwrap(),
+                dir_size + crate::fs::EXFAT_ENTRY_SIZE_U64 * (n64 - contiguous),
                 self.get_cluster_size()
             ),
             true,
         )?;
-        Ok(offset)
+        Ok(dir_size - crate::fs::EXFAT_ENTRY_SIZE_U64 * contiguous)
     }
 
     fn commit_entry(
@@ -1675,13 +2444,14 @@ impl Exfat {
         let checksum = crate::util::calc_checksum(&entries, 2 + name_entries);
         let meta1: &mut crate::fs::ExfatEntryMeta1 = bytemuck::cast_mut(&mut entries[0]);
         meta1.checksum = checksum;
+        self.flush()?; // Rust: same order as flush_node
         self.write_entries(dnid, &entries, 2 + name_entries, offset)?;
 
         let mut node = Self::alloc_node();
         node.entry_offset = offset;
         node.init_meta1(bytemuck::cast_ref(&entries[0]));
         node.init_meta2(bytemuck::cast_ref(&entries[1]));
-        node.init_name(&entries[2..], name_entries);
+        node.set_entries(entries.into()); // Rust: also keeps the name
         let nid = self.nmap_attach(dnid, node)?;
         assert!(get_node!(self, &nid).is_valid());
         Ok(nid)
@@ -1818,8 +2588,13 @@ impl Exfat {
         let name_length = crate::utf::utf16_length(name);
         let name_entries = crate::util::div_round_up!(name_length, crate::fs::EXFAT_ENAME_MAX);
 
-        let node = get_node!(self, &nid);
-        let mut entries = self.read_entries(node.pnid, 2, node.entry_offset)?;
+        // Rust
+        // relan/exfat reads meta1 and meta2 back from the directory, which
+        // is up to date once the node is flushed. FAT and clusters bitmap
+        // are then on disk before the entry set is written.
+        self.flush_node(nid)?;
+        self.flush()?;
+        let mut entries = get_node!(self, &nid).entries[..2].to_vec();
         let v = crate::fs::ExfatEntry::bulk_new(name_entries);
         entries.extend_from_slice(&v);
         assert_eq!(entries.len(), 2 + name_entries);
@@ -1831,10 +2606,14 @@ impl Exfat {
         meta2.name_length = name_length.try_into().unwrap();
         meta2.name_hash = crate::util::calc_name_hash(&self.upcase, name, name_length);
 
+        // Rust
+        // ino of the new location is allocated before anything is changed,
+        // so that attaching the node can't fail once it's detached
+        let ino = match self.opt.ino {
+            crate::option::InoMode::Nid => 0,
+            crate::option::InoMode::Entry => self.alloc_entry_ino(new_dnid, new_offset)?,
+        };
         self.erase_node(nid)?;
-        let node = get_node_mut!(self, &nid);
-        node.entry_offset = new_offset;
-        node.continuations = (1 + name_entries).try_into().unwrap();
 
         for i in 0..name_entries {
             let name_entry: &mut crate::fs::ExfatEntryName =
@@ -1850,16 +2629,31 @@ impl Exfat {
         let checksum = crate::util::calc_checksum(&entries, 2 + name_entries);
         let meta1: &mut crate::fs::ExfatEntryMeta1 = bytemuck::cast_mut(&mut entries[0]);
         meta1.checksum = checksum;
-        self.write_entries(new_dnid, &entries, 2 + name_entries, new_offset)?;
-
-        let node = get_node_mut!(self, &nid);
-        node.update_name(&entries[2..], name_entries);
-        assert!(node.is_valid());
+        if let Err(e) = self.write_entries(new_dnid, &entries, 2 + name_entries, new_offset) {
+            // Rust
+            // the node was flushed before erasing, so its entries match
+            // what was on disk; put them back to keep the file reachable
+            let node = get_node!(self, &nid);
+            let (old_entries, old_offset) = (node.entries.clone(), node.entry_offset);
+            if let Err(e) =
+                self.write_entries(old_dnid, &old_entries, old_entries.len(), old_offset)
+            {
+                log::error!("failed to restore entry set: {e}");
+            }
+            return Err(e);
+        }
 
         // update pnid / cnids to move nid from old_dnid to new_dnid
-        let node = self.nmap_detach_node(old_dnid, nid)?;
+        // (name and entries are updated while detached to keep name index
+        // and free entries index consistent)
+        let mut node = self.nmap_detach_node(old_dnid, nid)?;
         assert_eq!(node.nid, nid);
-        Ok(self.nmap_attach_node(new_dnid, node))
+        node.entry_offset = new_offset;
+        node.continuations = (1 + name_entries).try_into().unwrap();
+        node.set_entries(entries.into());
+        node.ino = ino;
+        assert!(node.is_valid());
+        Ok(self.nmap_attach_node(new_dnid, node)?)
     }
 
     /// # Errors
@@ -2030,7 +2824,16 @@ impl Exfat {
             Ok(v) => v,
             Err(e) => match e {
                 crate::Error::Errno(e) => match e {
-                    nix::errno::Errno::ENOENT => self.find_slot(crate::node::NID_ROOT, 1)?,
+                    nix::errno::Errno::ENOENT => {
+                        let offset = self.find_slot(crate::node::NID_ROOT, 1)?;
+                        // Rust
+                        // label has no node to take the slot
+                        if let Some(slots) = &mut get_node_mut!(self, &crate::node::NID_ROOT).slots
+                        {
+                            slots.remove(offset / crate::fs::EXFAT_ENTRY_SIZE_U64, 1);
+                        }
+                        offset
+                    }
                     _ => return Err(e.into()),
                 },
                 crate::Error::Error(e) => return Err(e.into()),
@@ -2051,6 +2854,15 @@ impl Exfat {
         Ok(())
     }
 
+    /// # Errors
+    pub fn set_volume_serial(&mut self, serial: u32) -> crate::Result<()> {
+        if self.ro != 0 {
+            return Err(nix::errno::Errno::EROFS.into());
+        }
+        self.sb.volume_serial = serial.to_le();
+        self.commit_boot_region()
+    }
+
     /// # Errors
     pub fn opendir_cursor(&mut self, dnid: crate::node::Nid) -> crate::Result<Cursor> {
         get_node_mut!(self, &dnid).get();
@@ -2122,21 +2934,19 @@ impl Exfat {
         n: usize,
     ) -> crate::Result<crate::node::Nid> {
         let buf = crate::utf::utf8_to_utf16(name.as_bytes(), NAME_MAX, n)?;
-        let mut c = self.opendir_cursor(dnid)?;
-        loop {
-            let nid = match self.readdir_cursor(&mut c) {
-                Ok(v) => v,
-                Err(e) => {
-                    self.closedir_cursor(c);
-                    return Err(e);
-                }
-            };
-            if self.compare_name(&buf, &get_node!(self, &nid).name) {
-                self.closedir_cursor(c);
+        // Rust
+        // relan/exfat compares the name against each child.
+        get_node_mut!(self, &dnid).get();
+        let result = self.cache_directory(dnid);
+        get_node_mut!(self, &dnid).put();
+        result?;
+        for nid in self.get_name_candidates(dnid, &buf) {
+            if self.compare_name(&buf, &get_node!(self, &nid).get_name16()) {
+                get_node_mut!(self, &nid).get(); // caller needs to put this node
                 return Ok(nid);
             }
-            get_node_mut!(self, &nid).put();
         }
+        Err(nix::errno::Errno::ENOENT.into())
     }
 
     /// # Errors
@@ -2246,7 +3056,7 @@ impl Exfat {
         panic!("impossible");
     }
 
-    fn ask_to_fix(&self) -> bool {
+    pub(crate) fn ask_to_fix(&self) -> bool {
         Self::ask_to_fix_(&self.opt.repair)
     }
 
@@ -2280,16 +3090,7 @@ impl Exfat {
 
     fn fix_invalid_vbr_checksum(&mut self, vbr_checksum: u32) -> std::io::Result<()> {
         let mut sector = vec![0; self.get_sector_size().try_into().unwrap()];
-        assert_eq!(sector.len() % std::mem::size_of::<u32>(), 0);
-        let x = std::mem::size_of_val(&vbr_checksum);
-        let n = sector.len() / x;
-        for i in 0..n {
-            let offset = x * i;
-            byteorder::LittleEndian::write_u32_into(
-                &[vbr_checksum.to_le()],
-                &mut sector[offset..offset + x],
-            );
-        }
+        Self::fill_vbr_checksum(&mut sector, vbr_checksum);
         if let Err(e) = self.dev.pwrite(&sector, 11 * self.get_sector_size()) {
             log::error!("failed to write correct VBR checksum");
             return Err(e);
@@ -2298,6 +3099,12 @@ impl Exfat {
         Ok(())
     }
 
+    fn fix_invalid_boot_region(&mut self, region: &[u8], backup: bool) -> std::io::Result<()> {
+        self.write_boot_region(region, backup)?;
+        self.count_errors_fixed();
+        Ok(())
+    }
+
     fn fix_invalid_node_checksum(&mut self, nid: crate::node::Nid) -> bool {
         // checksum will be rewritten by exfat_flush_node()
         get_node_mut!(self, &nid).is_dirty = true;
@@ -2350,52 +3157,222 @@ impl Exfat {
         Ok(u64::from(clusters) * self.get_cluster_size())
     }
 
-    fn verify_vbr_checksum(&mut self) -> crate::Result<()> {
+    fn read_boot_region(&mut self, backup: bool) -> std::io::Result<Vec<u8>> {
         let sector_size = self.get_sector_size();
-        let sector = match self.dev.preadx(sector_size, 0) {
-            Ok(v) => v,
+        let offset = if backup {
+            BOOT_REGION_SECTORS * sector_size
+        } else {
+            0
+        };
+        match self.dev.preadx(BOOT_REGION_SECTORS * sector_size, offset) {
+            Ok(v) => Ok(v),
             Err(e) => {
-                log::error!("failed to read boot sector");
-                return Err(e.into());
+                log::error!(
+                    "failed to read {} boot region",
+                    if backup { "backup" } else { "main" }
+                );
+                Err(e)
             }
+        }
+    }
+
+    fn write_boot_region(&mut self, region: &[u8], backup: bool) -> std::io::Result<()> {
+        let sector_size = self.get_sector_size();
+        assert_eq!(
+            u64::try_from(region.len()).unwrap(),
+            BOOT_REGION_SECTORS * sector_size
+        );
+        let offset = if backup {
+            BOOT_REGION_SECTORS * sector_size
+        } else {
+            0
         };
+        if let Err(e) = self.dev.pwrite(region, offset) {
+            log::error!(
+                "failed to write {} boot region",
+                if backup { "backup" } else { "main" }
+            );
+            return Err(e);
+        }
+        Ok(())
+    }
 
-        let mut vbr_checksum = crate::util::vbr_start_checksum(&sector, sector_size);
+    // Calculate checksum of boot sector and the following 10 sectors.
+    pub(crate) fn calc_vbr_checksum(region: &[u8], sector_size: u64) -> u32 {
+        let n = usize::try_from(sector_size).unwrap();
+        let mut vbr_checksum = crate::util::vbr_start_checksum(&region[..n], sector_size);
         for i in 1..11 {
-            let sector = match self.dev.preadx(sector_size, i * sector_size) {
-                Ok(v) => v,
-                Err(e) => {
-                    log::error!("failed to read VBR sector");
-                    return Err(e.into());
-                }
-            };
-            vbr_checksum = crate::util::vbr_add_checksum(&sector, sector_size, vbr_checksum);
+            vbr_checksum = crate::util::vbr_add_checksum(
+                &region[i * n..(i + 1) * n],
+                sector_size,
+                vbr_checksum,
+            );
         }
+        vbr_checksum
+    }
 
-        let sector = match self.dev.preadx(sector_size, 11 * sector_size) {
-            Ok(v) => v,
-            Err(e) => {
-                log::error!("failed to read VBR checksum sector");
-                return Err(e.into());
+    // Checksum sector is filled with repeated checksum values.
+    fn check_vbr_checksum(region: &[u8], sector_size: u64, vbr_checksum: u32) -> bool {
+        let n = usize::try_from(sector_size).unwrap();
+        let sector = &region[11 * n..12 * n];
+        let x = std::mem::size_of_val(&vbr_checksum);
+        for i in 0..sector.len() / x {
+            let offset = x * i;
+            let c = u32::from_le_bytes(sector[offset..offset + x].try_into().unwrap());
+            if c != vbr_checksum {
+                log::error!("invalid VBR checksum {c:#x} (expected {vbr_checksum:#x})");
+                return false;
             }
-        };
+        }
+        true
+    }
 
+    pub(crate) fn fill_vbr_checksum(sector: &mut [u8], vbr_checksum: u32) {
+        assert_eq!(sector.len() % std::mem::size_of::<u32>(), 0);
         let x = std::mem::size_of_val(&vbr_checksum);
         let n = sector.len() / x;
         for i in 0..n {
             let offset = x * i;
-            let c = u32::from_le_bytes(sector[offset..offset + x].try_into().unwrap());
-            if c != vbr_checksum {
-                log::error!("invalid VBR checksum {c:#x} (expected {vbr_checksum:#x})");
-                if !self.ask_to_fix() {
-                    return Err(nix::errno::Errno::ECANCELED.into());
+            byteorder::LittleEndian::write_u32_into(
+                &[vbr_checksum.to_le()],
+                &mut sector[offset..offset + x],
+            );
+        }
+    }
+
+    // volume_state and allocated_percent fields are excluded from checksum,
+    // and backup boot region isn't expected to follow changes made to them.
+    fn cmp_boot_region(a: &[u8], b: &[u8]) -> bool {
+        a.len() == b.len()
+            && a.iter()
+                .zip(b)
+                .enumerate()
+                .all(|(i, (x, y))| i == 0x6a || i == 0x6b || i == 0x70 || x == y)
+    }
+
+    fn verify_boot_region(&mut self) -> crate::Result<()> {
+        let sector_size = self.get_sector_size();
+        let mut main = self.read_boot_region(false)?;
+        let vbr_checksum = Self::calc_vbr_checksum(&main, sector_size);
+        let main_valid = Self::check_vbr_checksum(&main, sector_size, vbr_checksum);
+
+        // backup boot region is only needed for recovery, don't fail on it
+        let backup = match self.read_boot_region(true) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                log::error!("{e}");
+                None
+            }
+        };
+        let backup_valid = match &backup {
+            Some(v) => {
+                Self::check_vbr_checksum(v, sector_size, Self::calc_vbr_checksum(v, sector_size))
+                    && Self::verify_super_block(
+                        libfs::cast::align_to::<crate::fs::ExfatSuperBlock>(v),
+                    )
+                    .is_ok()
+            }
+            None => false,
+        };
+
+        if !main_valid {
+            if let Some(backup) = backup.as_ref().filter(|_| backup_valid) {
+                let sb = *libfs::cast::align_to::<crate::fs::ExfatSuperBlock>(backup);
+                if sb.sector_bits == self.sb.sector_bits {
+                    log::error!("main boot region is corrupted, backup boot region is valid");
+                    if !self.ask_to_fix() {
+                        return Err(nix::errno::Errno::ECANCELED.into());
+                    }
+                    self.fix_invalid_boot_region(backup, false)?;
+                    self.sb = sb;
+                    return Ok(());
                 }
-                self.fix_invalid_vbr_checksum(vbr_checksum)?;
             }
+            if !self.ask_to_fix() {
+                return Err(nix::errno::Errno::ECANCELED.into());
+            }
+            self.fix_invalid_vbr_checksum(vbr_checksum)?;
+            let n = usize::try_from(sector_size).unwrap();
+            Self::fill_vbr_checksum(&mut main[11 * n..12 * n], vbr_checksum);
+        }
+
+        let in_sync = match &backup {
+            Some(v) => backup_valid && Self::cmp_boot_region(&main, v),
+            None => false,
+        };
+        if !in_sync {
+            // keep mounting even if not fixed, main boot region is valid
+            log::error!("backup boot region does not match main boot region");
+            if self.ask_to_fix() {
+                self.fix_invalid_boot_region(&main, true)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Rust
+    // Backup boot region of the sector size it claims, checked for each
+    // sector size as the main super block can't be trusted to tell it.
+    fn find_backup_boot_region(&mut self) -> Option<Vec<u8>> {
+        for sector_bits in 9..=12 {
+            let sector_size = 1 << sector_bits;
+            let size = BOOT_REGION_SECTORS * sector_size;
+            let Ok(region) = self.dev.preadx(size, size) else {
+                continue;
+            };
+            let sb = libfs::cast::align_to::<crate::fs::ExfatSuperBlock>(&region);
+            if sb.sector_bits == sector_bits
+                && Self::verify_super_block(sb).is_ok()
+                && Self::check_vbr_checksum(
+                    &region,
+                    sector_size,
+                    Self::calc_vbr_checksum(&region, sector_size),
+                )
+            {
+                return Some(region);
+            }
+        }
+        None
+    }
+
+    fn verify_super_block(sb: &crate::fs::ExfatSuperBlock) -> crate::Result<()> {
+        if sb.oem_name != "EXFAT   ".as_bytes() {
+            log::error!("exFAT file system is not found");
+            return Err(nix::errno::Errno::EIO.into());
+        }
+        // sector cannot be smaller than 512 bytes
+        if sb.sector_bits < 9 {
+            log::error!("too small sector size: 2^{}", sb.sector_bits);
+            return Err(nix::errno::Errno::EIO.into());
+        }
+        // officially exFAT supports cluster size up to 32 MB
+        if sb.sector_bits + sb.spc_bits > 25 {
+            log::error!(
+                "too big cluster size: 2^({}+{})",
+                sb.sector_bits,
+                sb.spc_bits
+            );
+            return Err(nix::errno::Errno::EIO.into());
         }
         Ok(())
     }
 
+    // Unlike commit_super_block(), this updates both main and backup boot
+    // regions along with their checksum sectors, which is needed when fields
+    // covered by the checksum are modified.
+    fn commit_boot_region(&mut self) -> crate::Result<()> {
+        let sector_size = self.get_sector_size();
+        let mut region = self.read_boot_region(false)?;
+        region[..crate::fs::EXFAT_SUPER_BLOCK_SIZE]
+            .copy_from_slice(libfs::cast::as_u8_slice(&self.sb));
+        let vbr_checksum = Self::calc_vbr_checksum(&region, sector_size);
+        let n = usize::try_from(sector_size).unwrap();
+        Self::fill_vbr_checksum(&mut region[11 * n..12 * n], vbr_checksum);
+        self.write_boot_region(&region, false)?;
+        self.write_boot_region(&region, true)?;
+        self.fsync()
+    }
+
     fn commit_super_block(&mut self) -> crate::Result<()> {
         if let Err(e) = self.dev.pwrite(libfs::cast::as_u8_slice(&self.sb), 0) {
             log::error!("failed to write super block");
@@ -2454,26 +3431,20 @@ impl Exfat {
         ef.sb = *libfs::cast::align_to::<crate::fs::ExfatSuperBlock>(&buf);
         log::debug!("{:?}", ef.sb);
 
-        if ef.sb.oem_name != "EXFAT   ".as_bytes() {
-            log::error!("exFAT file system is not found");
-            return Err(nix::errno::Errno::EIO.into());
-        }
-        // sector cannot be smaller than 512 bytes
-        if ef.sb.sector_bits < 9 {
-            log::error!("too small sector size: 2^{}", ef.sb.sector_bits);
-            return Err(nix::errno::Errno::EIO.into());
-        }
-        // officially exFAT supports cluster size up to 32 MB
-        if ef.sb.sector_bits + ef.sb.spc_bits > 25 {
-            log::error!(
-                "too big cluster size: 2^({}+{})",
-                ef.sb.sector_bits,
-                ef.sb.spc_bits
-            );
-            return Err(nix::errno::Errno::EIO.into());
+        if let Err(e) = Self::verify_super_block(&ef.sb) {
+            // Rust
+            // relan/exfat doesn't look at backup boot region
+            let Some(backup) = ef.find_backup_boot_region() else {
+                return Err(e);
+            };
+            log::error!("main super block is corrupted, backup boot region is valid");
+            if !ef.ask_to_fix() {
+                return Err(nix::errno::Errno::ECANCELED.into());
+            }
+            ef.sb = *libfs::cast::align_to::<crate::fs::ExfatSuperBlock>(&backup);
+            ef.fix_invalid_boot_region(&backup, false)?;
         }
-
-        ef.verify_vbr_checksum()?;
+        ef.verify_boot_region()?;
 
         assert!(ef.zero_cluster.is_empty());
         ef.zero_cluster
@@ -2512,8 +3483,21 @@ impl Exfat {
             );
             return Err(nix::errno::Errno::EIO.into());
         }
-        if u16::from_le(ef.sb.volume_state) & crate::fs::EXFAT_STATE_MOUNTED != 0 {
+        let dirty = u16::from_le(ef.sb.volume_state) & crate::fs::EXFAT_STATE_MOUNTED != 0;
+        if dirty {
             log::warn!("volume was not unmounted cleanly");
+            // Rust
+            match ef.opt.dirty {
+                crate::option::DirtyMode::Ro if ef.ro == 0 => {
+                    log::warn!("mounting read-only");
+                    ef.ro = 1;
+                }
+                crate::option::DirtyMode::Fail if ef.ro == 0 => {
+                    log::error!("refusing to mount dirty volume read-write");
+                    return Err(nix::errno::Errno::EIO.into());
+                }
+                _ => (),
+            }
         }
 
         // Rust
@@ -2578,6 +3562,17 @@ impl Exfat {
             ef.remove_root_node()?;
             return Err(nix::errno::Errno::EIO.into());
         }
+        // Rust
+        if dirty {
+            if let crate::option::DirtyMode::Check = ef.opt.dirty {
+                if let Err(e) = ef.check_volume() {
+                    get_node_mut!(ef, &nid).put();
+                    ef.reset_node()?;
+                    ef.remove_root_node()?;
+                    return Err(e);
+                }
+            }
+        }
         Ok(ef)
     }
 
@@ -2605,7 +3600,14 @@ impl Exfat {
         self.flush_nodes()?;
         self.flush()?;
         get_node_mut!(self, &crate::node::NID_ROOT).put();
+        // Rust
+        log::debug!(
+            "nmap: {} nodes, {} bytes",
+            self.nmap.len(),
+            self.nmap.get_memory_usage()
+        );
         self.reset_node()?;
+        self.lru.clear(); // Rust
         self.dump_node_all();
         self.remove_root_node()?;
         self.finalize_super_block()?;
@@ -2635,9 +3637,16 @@ impl Exfat {
         // There is no such thing as inode in exFAT, but since FUSE ops
         // in fuser are built around ino (which is usually inode#),
         // return nid as ino.
+        // Rust
+        // With --ino=entry, return location of the entry set instead,
+        // which is stable across mounts and prune_node.
+        let ino = match self.opt.ino {
+            crate::option::InoMode::Nid => node.nid,
+            crate::option::InoMode::Entry => self.get_ino(nid),
+        };
         Ok(Stat {
             st_dev: 0,
-            st_ino: node.nid,
+            st_ino: ino,
             st_nlink: 1,
             st_mode: mode,
             st_uid: self.opt.uid,
@@ -2651,6 +3660,7 @@ impl Exfat {
             // set ctime to mtime to ensure we don't break programs that rely on ctime
             // (e.g. rsync)
             st_ctime: node.mtime,
+            st_gen: node.get_generation(),
         })
     }
 
@@ -2698,6 +3708,179 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_exfat_vbr_checksum() {
+        let sector_size = 512;
+        let n = usize::try_from(sector_size).unwrap();
+        let mut region = vec![0; n * usize::try_from(super::BOOT_REGION_SECTORS).unwrap()];
+        for (i, x) in region.iter_mut().enumerate().take(11 * n) {
+            *x = (i % 251).try_into().unwrap();
+        }
+        let vbr_checksum = super::Exfat::calc_vbr_checksum(&region, sector_size);
+        assert!(!super::Exfat::check_vbr_checksum(
+            &region,
+            sector_size,
+            vbr_checksum
+        ));
+        super::Exfat::fill_vbr_checksum(&mut region[11 * n..12 * n], vbr_checksum);
+        assert!(super::Exfat::check_vbr_checksum(
+            &region,
+            sector_size,
+            vbr_checksum
+        ));
+
+        // volume_state and allocated_percent don't affect checksum
+        region[0x6a] ^= 0xff;
+        region[0x70] ^= 0xff;
+        assert_eq!(
+            super::Exfat::calc_vbr_checksum(&region, sector_size),
+            vbr_checksum
+        );
+        region[0x64] ^= 0xff;
+        assert_ne!(
+            super::Exfat::calc_vbr_checksum(&region, sector_size),
+            vbr_checksum
+        );
+    }
+
+    #[test]
+    fn test_exfat_cmp_boot_region() {
+        let a = vec![0; 512 * 12];
+        let mut b = a.clone();
+        assert!(super::Exfat::cmp_boot_region(&a, &b));
+        b[0x6a] = 1;
+        b[0x6b] = 1;
+        b[0x70] = 1;
+        assert!(super::Exfat::cmp_boot_region(&a, &b));
+        b[0x6c] = 1;
+        assert!(!super::Exfat::cmp_boot_region(&a, &b));
+        assert!(!super::Exfat::cmp_boot_region(&a, &a[..512]));
+    }
+
+    #[test]
+    fn test_exfat_cluster_map_dirty() {
+        let mut cmap = super::ClusterMap::new();
+        cmap.chunk = libfs::bitmap::Bitmap::new(8 * 512 * 4).unwrap();
+        cmap.sector_size = 512;
+        cmap.count = 8 * 512 * 4;
+        cmap.rebuild();
+        assert!(!cmap.is_dirty());
+        assert!(cmap.get_dirty_ranges().is_empty());
+
+        cmap.set_cluster(2).unwrap(); // sector 0
+        cmap.set_cluster(2 + 8 * 512 * 2).unwrap(); // sector 2
+        cmap.clear_cluster(2 + 8 * 512 + 7).unwrap(); // sector 1
+        assert!(cmap.is_dirty());
+        assert!(cmap.chunk.is_set(0).unwrap());
+        assert_eq!(cmap.get_dirty_ranges(), [(0, 512 * 3)]);
+        cmap.dirty.clear();
+
+        cmap.mark_dirty(8 * 512 * 3 + 1); // sector 3
+        cmap.mark_dirty(8 * 511); // sector 0
+        assert_eq!(cmap.get_dirty_ranges(), [(0, 512), (512 * 3, 512)]);
+        cmap.dirty.clear();
+
+        cmap.mark_all_dirty();
+        assert_eq!(cmap.get_dirty_ranges(), [(0, 512 * 4)]);
+    }
+
+    #[test]
+    fn test_exfat_cluster_map_run() {
+        let mut cmap = super::ClusterMap::new();
+        cmap.chunk = libfs::bitmap::Bitmap::new(8 * 512).unwrap();
+        cmap.sector_size = 512;
+        cmap.count = 60;
+        cmap.rebuild();
+        assert_eq!(cmap.find_best_run(1), Some((0, 60)));
+        assert_eq!(cmap.find_best_run(100), Some((0, 60)));
+
+        // free runs: [0,3) [4,20) [21,23) [30,60)
+        for i in [3, 20, 23, 24, 25, 26, 27, 28, 29] {
+            cmap.set_cluster(2 + i).unwrap();
+        }
+        assert_eq!(cmap.find_best_run(1), Some((21, 2)));
+        assert_eq!(cmap.find_best_run(2), Some((21, 2)));
+        assert_eq!(cmap.find_best_run(3), Some((0, 3)));
+        assert_eq!(cmap.find_best_run(4), Some((4, 16)));
+        assert_eq!(cmap.find_best_run(17), Some((30, 30)));
+        assert_eq!(cmap.find_best_run(31), Some((30, 30)));
+        cmap.chunk.set(61).unwrap(); // beyond count
+        assert_eq!(cmap.find_best_run(31), Some((30, 30)));
+
+        assert_eq!(cmap.get_run_length(4, 100), 16);
+        assert_eq!(cmap.get_run_length(4, 5), 5);
+        assert_eq!(cmap.get_run_length(3, 5), 0);
+        assert_eq!(cmap.get_run_length(50, 100), 10);
+        assert_eq!(cmap.find_next_free(3), Some(4));
+        assert_eq!(cmap.find_next_free(59), Some(59));
+        cmap.set_cluster(2 + 59).unwrap();
+        assert_eq!(cmap.find_next_free(59), Some(0)); // wrap around
+
+        for i in 0..60 {
+            if !cmap.chunk.is_set(i).unwrap() {
+                cmap.set_cluster(2 + u32::try_from(i).unwrap()).unwrap();
+            }
+        }
+        assert_eq!(cmap.free, 0);
+        assert_eq!(cmap.find_best_run(1), None);
+    }
+
+    #[test]
+    fn test_exfat_cluster_map_find_run() {
+        let mut cmap = super::ClusterMap::new();
+        cmap.chunk = libfs::bitmap::Bitmap::new(8 * 512).unwrap();
+        cmap.sector_size = 512;
+        cmap.count = 60;
+        cmap.rebuild();
+        assert_eq!(cmap.find_run(0, false), Some((0, 60)));
+        assert_eq!(cmap.find_run(0, true), None);
+        assert_eq!(cmap.find_run(60, false), None);
+
+        // used runs: [3,4) [20,30)
+        for i in [3, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29] {
+            cmap.set_cluster(2 + i).unwrap();
+        }
+        cmap.chunk.set(61).unwrap(); // beyond count
+        assert_eq!(cmap.find_run(0, false), Some((0, 3)));
+        assert_eq!(cmap.find_run(1, false), Some((1, 2)));
+        assert_eq!(cmap.find_run(3, false), Some((4, 16)));
+        assert_eq!(cmap.find_run(20, false), Some((30, 30)));
+        assert_eq!(cmap.find_run(0, true), Some((3, 1)));
+        assert_eq!(cmap.find_run(4, true), Some((20, 10)));
+        assert_eq!(cmap.find_run(25, true), Some((25, 5)));
+        assert_eq!(cmap.find_run(30, true), None);
+    }
+
+    #[test]
+    fn test_exfat_cluster_map_free() {
+        let mut cmap = super::ClusterMap::new();
+        cmap.chunk = libfs::bitmap::Bitmap::new(8 * 512).unwrap();
+        cmap.sector_size = 512;
+        cmap.count = 8 * 512 - 3; // trailing bits aren't clusters
+        cmap.rebuild();
+        assert_eq!(cmap.free, cmap.count);
+
+        cmap.set_cluster(2).unwrap();
+        cmap.set_cluster(2).unwrap(); // already set
+        cmap.set_cluster(2 + 100).unwrap();
+        assert_eq!(cmap.free, cmap.count - 2);
+        cmap.clear_cluster(2).unwrap();
+        cmap.clear_cluster(2).unwrap(); // already clear
+        assert_eq!(cmap.free, cmap.count - 1);
+        assert_eq!(cmap.free, cmap.count_free());
+
+        cmap.chunk.set(8 * 512 - 1).unwrap(); // beyond count
+        assert_eq!(cmap.count_free(), cmap.count - 1);
+
+        let mut chunk = libfs::bitmap::Bitmap::new(8 * 512).unwrap();
+        for i in 0..10 {
+            chunk.set(i).unwrap();
+        }
+        cmap.set_chunk(chunk);
+        assert_eq!(cmap.free, cmap.count - 10);
+        assert!(cmap.is_dirty());
+    }
+
     const EXFAT_DEBUG: &str = "EXFAT_DEBUG"; // option
     const EXFAT_DEVICE: &str = "EXFAT_DEVICE";
     const EXFAT_PATH: &str = "EXFAT_PATH";
@@ -2827,4 +4010,347 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_exfat_unlink_unterminated_chain() {
+        let img = crate::testimg::TestImage::new("unterminated", 1024);
+        let mut ef = img.mount(&["--noatime"]);
+        let a = ef.mknod("/a").unwrap();
+        let b = ef.mknod("/b").unwrap();
+        for nid in [a, b] {
+            get_node_mut!(ef, &nid).get();
+        }
+        crate::testimg::write_clusters(&mut ef, &[a, b], 3);
+        for nid in [a, b] {
+            ef.flush_node(nid).unwrap();
+            get_node_mut!(ef, &nid).put();
+        }
+        assert!(!get_node!(ef, &a).is_contiguous);
+        let runs = ef.get_cluster_runs(a).unwrap();
+        let last = runs.last().map(|x| x.0 + x.1 - 1).unwrap();
+        let fat = u64::from(ef.get_super_block().fat_sector_start) * ef.get_sector_size();
+        ef.unmount().unwrap();
+
+        // last cluster points to a free cluster instead of END
+        img.pwrite(&1000_u32.to_le_bytes(), fat + u64::from(last) * 4);
+        let mut ef = img.mount(&["--noatime"]);
+        let a = ef.lookup("/a").unwrap();
+        let free = ef.get_free_clusters().unwrap();
+        ef.unlink(a).unwrap();
+        assert_eq!(ef.get_free_clusters().unwrap(), free + 3);
+        assert!(!ef.is_cluster_allocated(1000 - 2).unwrap());
+        assert!(ef.lookup("/a").is_err());
+        assert!(ef.check_bitmap().unwrap().is_clean());
+        ef.unmount().unwrap();
+    }
+
+    #[test]
+    fn test_exfat_flush_node_writes_fat() {
+        let img = crate::testimg::TestImage::new("flushfat", 1024);
+        let mut ef = img.mount(&["--noatime"]);
+        let a = ef.mknod("/a").unwrap();
+        let b = ef.mknod("/b").unwrap();
+        for nid in [a, b] {
+            get_node_mut!(ef, &nid).get();
+        }
+        crate::testimg::write_clusters(&mut ef, &[a, b], 3);
+        ef.flush_node(a).unwrap();
+        assert!(!get_node!(ef, &a).is_contiguous);
+
+        // chain of a is on disk while still mounted
+        let fat = u64::from(ef.get_super_block().fat_sector_start) * ef.get_sector_size();
+        let clusters = ef
+            .get_cluster_runs(a)
+            .unwrap()
+            .iter()
+            .flat_map(|x| x.0..x.0 + x.1)
+            .collect::<Vec<_>>();
+        assert_eq!(clusters.len(), 3);
+        let next = clusters[1..]
+            .iter()
+            .copied()
+            .chain([crate::fs::EXFAT_CLUSTER_END]);
+        for (cluster, next) in clusters.iter().zip(next) {
+            let buf = img.pread(4, fat + u64::from(*cluster) * 4);
+            assert_eq!(u32::from_le_bytes(buf.try_into().unwrap()), next);
+        }
+        for nid in [a, b] {
+            ef.flush_node(nid).unwrap();
+            get_node_mut!(ef, &nid).put();
+        }
+        ef.unmount().unwrap();
+    }
+
+    #[test]
+    fn test_exfat_fallocate() {
+        let img = crate::testimg::TestImage::new("fallocate", 1024);
+        let mut ef = img.mount(&["--noatime"]);
+        let cluster_size = ef.get_cluster_size();
+        let n = usize::try_from(cluster_size).unwrap();
+        let nid = ef.mknod("/a").unwrap();
+        get_node_mut!(ef, &nid).get();
+
+        // leave stale data in the clusters which are allocated next
+        crate::testimg::write_clusters(&mut ef, &[nid], 4);
+        ef.truncate(nid, 0, true).unwrap();
+
+        ef.fallocate(nid, 0, 4 * cluster_size, super::FallocMode::Allocate)
+            .unwrap();
+
+        // no clusters beyond size, nothing is changed
+        let free = ef.get_free_clusters().unwrap();
+        assert!(matches!(
+            ef.fallocate(nid, 0, 5 * cluster_size, super::FallocMode::KeepSize),
+            Err(crate::Error::Errno(nix::errno::Errno::EOPNOTSUPP))
+        ));
+        ef.fallocate(nid, cluster_size, cluster_size, super::FallocMode::KeepSize)
+            .unwrap();
+        assert_eq!(ef.get_free_clusters().unwrap(), free);
+        let node = get_node!(ef, &nid);
+        assert_eq!((node.size, node.valid_size), (4 * cluster_size, 0));
+        assert_eq!(ef.read_all(nid).unwrap(), vec![0; 4 * n]);
+
+        // valid size is grown over the stale data
+        ef.set_valid_size(nid, cluster_size + 10).unwrap();
+        assert_eq!(get_node!(ef, &nid).valid_size, cluster_size + 10);
+        assert_eq!(ef.read_all(nid).unwrap(), vec![0; 4 * n]);
+        assert!(ef.set_valid_size(nid, 5 * cluster_size).is_err());
+
+        // the gap between valid size and the offset reads as zeroes
+        ef.pwrite(nid, b"abc", 3 * cluster_size).unwrap();
+        assert_eq!(get_node!(ef, &nid).valid_size, 3 * cluster_size + 3);
+        let mut expected = vec![0; 4 * n];
+        expected[3 * n..3 * n + 3].copy_from_slice(b"abc");
+        assert_eq!(ef.read_all(nid).unwrap(), expected);
+
+        // only the part within valid size is written
+        ef.fallocate(nid, 3 * cluster_size + 1, 10, super::FallocMode::ZeroRange)
+            .unwrap();
+        expected[3 * n + 1..3 * n + 3].fill(0);
+        assert_eq!(ef.read_all(nid).unwrap(), expected);
+        ef.fallocate(nid, 0, 5 * cluster_size, super::FallocMode::ZeroRange)
+            .unwrap();
+        let node = get_node!(ef, &nid);
+        assert_eq!(
+            (node.size, node.valid_size),
+            (5 * cluster_size, 3 * cluster_size + 3)
+        );
+        ef.flush_node(nid).unwrap();
+        get_node_mut!(ef, &nid).put();
+        ef.unmount().unwrap();
+
+        let mut ef = img.mount(&["--noatime"]);
+        let nid = ef.lookup("/a").unwrap();
+        let node = get_node!(ef, &nid);
+        assert_eq!(
+            (node.size, node.valid_size),
+            (5 * cluster_size, 3 * cluster_size + 3)
+        );
+        assert_eq!(ef.read_all(nid).unwrap(), vec![0; 5 * n]);
+        get_node_mut!(ef, &nid).put();
+        ef.unmount().unwrap();
+    }
+
+    #[test]
+    fn test_exfat_find_slot() {
+        let img = crate::testimg::TestImage::new("findslot", 1024);
+        let mut ef = img.mount(&["--noatime"]);
+        let cluster_size = ef.get_cluster_size();
+        let d = ef.mkdir("/d").unwrap();
+        get_node_mut!(ef, &d).get();
+        let slot = |ef: &mut super::Exfat, nid| {
+            get_node!(ef, &nid).entry_offset / crate::fs::EXFAT_ENTRY_SIZE_U64
+        };
+
+        // each of the short names takes 3 entries
+        for i in 0..10 {
+            let nid = ef.mknod_at(d, &format!("f{i}")).unwrap();
+            assert_eq!(slot(&mut ef, nid), i * 3);
+        }
+        for name in ["f5", "f6", "f8"] {
+            let nid = ef.lookup_at(d, name).unwrap();
+            ef.unlink(nid).unwrap();
+        }
+
+        // first fit, not the best one at 24
+        let nid = ef.mknod_at(d, "x").unwrap();
+        assert_eq!(slot(&mut ef, nid), 15);
+        let nid = ef.rename_at(d, "f9", d, "y").unwrap();
+        assert_eq!(slot(&mut ef, nid), 18);
+        // 24..30 is free now
+        let nid = ef.mknod_at(d, "z".repeat(20).as_str()).unwrap();
+        assert_eq!(slot(&mut ef, nid), 24);
+
+        // fill the first cluster and grow the directory
+        let nentries = cluster_size / crate::fs::EXFAT_ENTRY_SIZE_U64;
+        let mut i = 0;
+        while get_node!(ef, &d).size == cluster_size {
+            ef.mknod_at(d, &format!("g{i}")).unwrap();
+            i += 1;
+        }
+        assert_eq!(get_node!(ef, &d).size, 2 * cluster_size);
+        // free entries at 28 and the end are reused, the last entry set
+        // crosses the cluster boundary
+        let nid = ef.lookup_at(d, "g0").unwrap();
+        assert_eq!(slot(&mut ef, nid), 28);
+        get_node_mut!(ef, &nid).put();
+        let nid = ef.lookup_at(d, &format!("g{}", i - 1)).unwrap();
+        assert_eq!(slot(&mut ef, nid), nentries - 1);
+        get_node_mut!(ef, &nid).put();
+        ef.flush_node(d).unwrap();
+        get_node_mut!(ef, &d).put();
+        ef.unmount().unwrap();
+
+        // entry sets don't overlap and all of them are found
+        let mut ef = img.mount(&["--noatime"]);
+        let d = ef.lookup("/d").unwrap();
+        let mut v = ef
+            .readdir(d)
+            .unwrap()
+            .iter()
+            .map(|x| {
+                let node = get_node!(ef, x);
+                (node.entry_offset, node.continuations)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(v.len(), 7 + 1 + 1 + i);
+        v.sort_unstable();
+        for x in v.windows(2) {
+            let end = x[0].0 + (1 + u64::from(x[0].1)) * crate::fs::EXFAT_ENTRY_SIZE_U64;
+            assert!(end <= x[1].0);
+        }
+        get_node_mut!(ef, &d).put();
+        ef.unmount().unwrap();
+    }
+
+    #[test]
+    fn test_exfat_cache_large_directory() {
+        let img = crate::testimg::TestImage::new("largedir", 1024);
+        let mut ef = img.mount(&["--noatime"]);
+        let d = ef.mkdir("/d").unwrap();
+        let f = ef.mknod("/f").unwrap();
+        for nid in [d, f] {
+            get_node_mut!(ef, &nid).get();
+        }
+        // names up to 255 characters, so that entry sets cross boundaries
+        // of clusters and read windows
+        let names = (0..450)
+            .map(|i| format!("{i:03}{}", "n".repeat(i * 7 % 250)))
+            .collect::<Vec<_>>();
+        for (i, name) in names.iter().enumerate() {
+            ef.mknod_at(d, name).unwrap();
+            if i % 20 == 0 {
+                crate::testimg::write_clusters(&mut ef, &[f], 1);
+            }
+        }
+        assert!(get_node!(ef, &d).size > 2 * super::DIR_READ_SIZE);
+        assert!(ef.get_fragments(d).unwrap() > 1);
+        for nid in [d, f] {
+            ef.flush_node(nid).unwrap();
+            get_node_mut!(ef, &nid).put();
+        }
+        ef.unmount().unwrap();
+
+        let mut ef = img.mount(&["--noatime"]);
+        let d = ef.lookup("/d").unwrap();
+        let mut v = ef
+            .readdir(d)
+            .unwrap()
+            .iter()
+            .map(|x| get_node!(ef, x).get_name())
+            .collect::<Vec<_>>();
+        v.sort_unstable();
+        assert_eq!(v, names);
+        let nid = ef.lookup_at(d, &names[449]).unwrap();
+        get_node_mut!(ef, &nid).put();
+        get_node_mut!(ef, &d).put();
+        ef.unmount().unwrap();
+    }
+
+    #[test]
+    fn test_exfat_flush_after_failed_rename() {
+        let img = crate::testimg::TestImage::new("failedrename", 1024);
+        let mut ef = img.mount(&["--noatime"]);
+        let nid = ef.mknod("/a").unwrap();
+        get_node_mut!(ef, &nid).get();
+        ef.pwrite(nid, b"hello", 0).unwrap();
+
+        // the entry set is erased, but writing the new one fails
+        let name = crate::utf::utf8_to_utf16(b"b", super::NAME_MAX, 1).unwrap();
+        assert!(ef
+            .rename_entry(
+                crate::node::NID_ROOT,
+                crate::node::NID_ROOT,
+                nid,
+                &name,
+                1 << 50
+            )
+            .is_err());
+
+        // the original entry set is restored on disk
+        assert!(!get_node!(ef, &nid).is_dirty);
+        get_node_mut!(ef, &nid).put();
+        ef.unmount().unwrap();
+
+        let mut ef = img.mount(&["--noatime"]);
+        ef.rename("/a", "/b").unwrap();
+        assert!(ef.lookup("/a").is_err());
+        let nid = ef.lookup("/b").unwrap();
+        assert_eq!(ef.read_all(nid).unwrap(), b"hello");
+        get_node_mut!(ef, &nid).put();
+        ef.unmount().unwrap();
+    }
+
+    #[test]
+    fn test_exfat_failed_rename_entry_ino() {
+        let img = crate::testimg::TestImage::new("failedino", 1024);
+        let mut ef = img.mount(&["--noatime", "--ino", "entry"]);
+        let nid = ef.mknod("/a").unwrap();
+        get_node_mut!(ef, &nid).get();
+        let ino = ef.stat(nid).unwrap().st_ino;
+
+        // the new location has no ino, nothing is changed
+        let name = crate::utf::utf8_to_utf16(b"b", super::NAME_MAX, 1).unwrap();
+        assert!(ef
+            .rename_entry(
+                crate::node::NID_ROOT,
+                crate::node::NID_ROOT,
+                nid,
+                &name,
+                1 << 30
+            )
+            .is_err());
+        assert_eq!(ef.stat(nid).unwrap().st_ino, ino);
+        let gen = get_node!(ef, &nid).get_generation();
+        assert_eq!(ef.lookup_ino(ino, gen).unwrap(), nid);
+        get_node_mut!(ef, &nid).put();
+        ef.unmount().unwrap();
+
+        let mut ef = img.mount(&["--noatime", "--ino", "entry"]);
+        let nid = ef.lookup("/a").unwrap();
+        assert_eq!(ef.stat(nid).unwrap().st_ino, ino);
+        get_node_mut!(ef, &nid).put();
+        ef.unmount().unwrap();
+    }
+
+    #[test]
+    fn test_exfat_mount_backup_boot_region() {
+        let img = crate::testimg::TestImage::new("backupboot", 1024);
+        let region = img.pread(12 * 512, 0);
+        for (offset, value) in [(3, b'X'), (0x6c, 0), (0x6d, 30)] {
+            // checksum of the main boot region is still valid
+            img.pwrite(&[value], offset);
+            assert!(super::Exfat::mount(&img.path, &["--noatime"]).is_err());
+            let mut ef = img.mount(&["--noatime", "--repair", "yes"]);
+            assert_eq!(ef.get_errors_fixed(), 1);
+            assert_eq!(ef.get_label(), "TEST");
+            ef.unmount().unwrap();
+            assert_eq!(img.pread(12 * 512, 0), region);
+        }
+
+        // both are corrupted
+        img.pwrite(&[0], 0x6c);
+        img.pwrite(&[0], 12 * 512 + 0x6c);
+        assert!(super::Exfat::mount(&img.path, &["--noatime", "--repair", "yes"]).is_err());
+    }
 }
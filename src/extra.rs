@@ -26,6 +26,11 @@ impl crate::exfat::Exfat {
         self.sb.get_cluster_size()
     }
 
+    #[must_use]
+    pub fn get_volume_serial(&self) -> u32 {
+        u32::from_le(self.sb.volume_serial)
+    }
+
     #[must_use]
     pub fn is_readonly(&self) -> bool {
         self.ro != 0 // either 1 or -1
@@ -151,6 +156,54 @@ impl crate::exfat::Exfat {
         self.nmap.get_mut(&nid)
     }
 
+    // Inode number with --ino=entry, root has none on disk.
+    pub(crate) fn get_ino(&self, nid: crate::node::Nid) -> u64 {
+        if nid == crate::node::NID_ROOT {
+            crate::node::NID_ROOT
+        } else {
+            crate::exfat::get_node!(self, &nid).ino
+        }
+    }
+
+    // Reverse of stat, for file handles which outlive nid, e.g. NFS.
+    // Fails with ESTALE if the entry set was deleted or renamed since.
+    // With --ino=entry, ino of a node which isn't cached, e.g. after remount
+    // or eviction, is checked on disk first, then the tree is cached from
+    // root until the node shows up, as the entry set has no pointer to its
+    // parent directory.
+    /// # Errors
+    pub fn lookup_ino(&mut self, ino: u64, gen: u32) -> crate::Result<crate::node::Nid> {
+        let nid = match self.opt.ino {
+            crate::option::InoMode::Nid => ino,
+            crate::option::InoMode::Entry if ino == crate::node::NID_ROOT => ino,
+            crate::option::InoMode::Entry => match self.inos.get(&ino) {
+                Some(v) => *v,
+                None => self.find_entry_ino(ino, gen)?,
+            },
+        };
+        let Some(node) = self.nmap.get(&nid) else {
+            return Err(nix::errno::Errno::ENOENT.into());
+        };
+        if node.get_generation() != gen {
+            return Err(nix::errno::Errno::ESTALE.into());
+        }
+        Ok(nid)
+    }
+
+    // The node is referenced while the walk evicts nodes beyond --maxmem.
+    fn find_entry_ino(&mut self, ino: u64, gen: u32) -> crate::Result<crate::node::Nid> {
+        self.check_entry_ino(ino, gen)?;
+        let nid = self.with_all_cached(|ef| match ef.inos.get(&ino) {
+            Some(v) => {
+                crate::exfat::get_node_mut!(ef, v).get();
+                Ok(*v)
+            }
+            None => Err(nix::errno::Errno::ENOENT.into()),
+        })?;
+        crate::exfat::get_node_mut!(self, &nid).put();
+        Ok(nid)
+    }
+
     #[must_use]
     pub fn get_errors(&self) -> usize {
         self.errors // XXX unsupported, always 0
@@ -167,6 +220,7 @@ impl crate::exfat::Exfat {
 
     /// # Errors
     pub fn fsync(&mut self) -> crate::Result<()> {
+        self.flush()?; // write back cached FAT and clusters bitmap
         if let Err(e) = self.dev.fsync() {
             log::error!("fsync failed: {e}");
             return Err(e.into());
@@ -179,18 +233,6 @@ impl crate::exfat::Exfat {
         Ok(self.cmap.chunk.is_set(index)?)
     }
 
-    pub(crate) fn ffas_cluster(&mut self, start: u32, end: u32) -> nix::Result<u32> {
-        let index = self
-            .cmap
-            .chunk
-            .set_from_range(start.try_into().unwrap(), end.try_into().unwrap())?;
-        if index == usize::MAX {
-            Err(nix::errno::Errno::ENOSPC)
-        } else {
-            Ok(crate::fs::EXFAT_FIRST_DATA_CLUSTER + u32::try_from(index).unwrap())
-        }
-    }
-
     fn ffas_nid(
         &mut self,
         start: crate::node::Nid,
@@ -265,7 +307,7 @@ impl crate::exfat::Exfat {
 
         self.dump_node_all();
 
-        let xname = crate::exfat::get_node!(self, &xnid).get_name().to_string();
+        let xname = crate::exfat::get_node!(self, &xnid).get_name16();
         self.recache_directory(crate::node::NID_ROOT, &xname)?;
         let c = self.nmap.len();
         assert!(c >= b);
@@ -315,6 +357,89 @@ impl crate::exfat::Exfat {
         Ok(())
     }
 
+    // A subtree is busy if it contains xnid, or a node which is referenced
+    // or dirty.
+    fn is_subtree_busy(&self, nid: crate::node::Nid, xnid: crate::node::Nid) -> bool {
+        let mut x = xnid;
+        while x != crate::node::NID_NONE {
+            if x == nid {
+                return true;
+            }
+            x = crate::exfat::get_node!(self, &x).pnid;
+        }
+        let mut stack = vec![nid];
+        while let Some(nid) = stack.pop() {
+            let node = crate::exfat::get_node!(self, &nid);
+            if node.references > 0 || node.is_dirty {
+                return true;
+            }
+            stack.extend(&node.cnids);
+        }
+        false
+    }
+
+    // Memory used by nodes under nid, which is given back by pruning it.
+    fn get_subtree_usage(&self, nid: crate::node::Nid) -> usize {
+        let mut usage = 0;
+        let mut stack = crate::exfat::get_node!(self, &nid).cnids.clone();
+        while let Some(nid) = stack.pop() {
+            let node = crate::exfat::get_node!(self, &nid);
+            usage += crate::node::NodeMap::get_node_usage(node);
+            stack.extend(&node.cnids);
+        }
+        usage
+    }
+
+    // Evict nodes if they use more memory than --maxmem, unless eviction is
+    // held off by a whole-tree walk.
+    pub(crate) fn evict_nodes_if_needed(&mut self, xnid: crate::node::Nid) -> nix::Result<()> {
+        if self.opt.maxmem != 0
+            && self.evict_held == 0
+            && self.nmap.get_memory_usage() > self.opt.maxmem
+        {
+            self.evict_nodes(xnid)?;
+        }
+        Ok(())
+    }
+
+    // Evict least recently used directories until memory used by nodes is
+    // within --maxmem. Busy subtrees are left cached. Usage is measured once
+    // and then reduced by what each eviction gives back, as the map only
+    // releases memory when shrunk at the end.
+    pub(crate) fn evict_nodes(&mut self, xnid: crate::node::Nid) -> nix::Result<()> {
+        let a = self.nmap.len();
+        let mut usage = self.nmap.get_memory_usage();
+        let mut busy = vec![];
+        while usage > self.opt.maxmem {
+            let Some((stamp, nid)) = self.lru.pop() else {
+                break;
+            };
+            match self.nmap.get(&nid) {
+                Some(node) if node.lru == stamp && node.is_cached => (),
+                _ => continue, // removed or evicted
+            }
+            if self.is_subtree_busy(nid, xnid) {
+                busy.push((stamp, nid));
+                continue;
+            }
+            usage = usage.saturating_sub(self.get_subtree_usage(nid));
+            // none of prune_node_impl's busy conditions apply
+            self.prune_node_impl(nid, crate::node::NID_NONE)?;
+            let node = crate::exfat::get_node_mut!(self, &nid);
+            node.lru = 0;
+            node.names = crate::node::NameIndex::default();
+            node.slots = None;
+        }
+        for (stamp, nid) in busy {
+            self.lru.insert(stamp, nid);
+        }
+        if self.nmap.len() != a {
+            self.nmap.shrink_to_fit();
+        }
+        log::debug!("{} node evicted", a - self.nmap.len());
+        Ok(())
+    }
+
     #[allow(dead_code)]
     pub(crate) fn dump_node(&self, nid: crate::node::Nid) {
         self.dump_node_impl(nid, 0);
@@ -339,3 +464,150 @@ impl crate::exfat::Exfat {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_evict_nodes() {
+        let img = crate::testimg::TestImage::new("evict", 1024);
+        let mut ef = img.mount(&["--noatime"]);
+        for i in 0..10 {
+            ef.mkdir(&format!("/d{i}")).unwrap();
+        }
+        ef.mkdir("/d2/s").unwrap();
+        for i in 0..10 {
+            let n = if i == 2 { 4 } else { 5 };
+            for j in 0..n {
+                ef.mknod(&format!("/d{i}/f{j}")).unwrap();
+            }
+        }
+        for j in 0..5 {
+            ef.mknod(&format!("/d2/s/f{j}")).unwrap();
+        }
+        ef.unmount().unwrap();
+
+        // root and its 10 directories, each of which adds 5 nodes once cached
+        let maxmem = usize::MAX.to_string();
+        let mut ef = img.mount(&["--noatime", "--maxmem", &maxmem]);
+        assert_eq!(ef.nmap.len(), 11);
+        let referenced = ef.lookup("/d0/f0").unwrap();
+        let dirty = ef.lookup("/d1/f0").unwrap();
+        crate::exfat::get_node_mut!(ef, &dirty).put();
+        crate::exfat::get_node_mut!(ef, &dirty).is_dirty = true;
+        let s = ef.lookup("/d2/s").unwrap();
+        crate::exfat::get_node_mut!(ef, &s).put();
+        assert_eq!(ef.nmap.len(), 26);
+        ef.opt.maxmem = ef.nmap.get_memory_usage();
+
+        // nothing to evict, d2 is an ancestor of the directory being cached
+        ef.cache_directory(s).unwrap();
+        assert_eq!(ef.nmap.len(), 31);
+        let d2 = crate::exfat::get_node!(ef, &s).pnid;
+        assert!(crate::exfat::get_node!(ef, &d2).is_cached);
+
+        // d2 is evicted along with s, busy ones are left cached
+        let d3 = ef.lookup("/d3").unwrap();
+        ef.readdir(d3).unwrap();
+        assert_eq!(ef.nmap.len(), 26);
+        assert!(!crate::exfat::get_node!(ef, &d2).is_cached);
+        assert!(!ef.nmap.contains_key(&s));
+        for nid in [referenced, dirty] {
+            let dnid = crate::exfat::get_node!(ef, &nid).pnid;
+            assert!(crate::exfat::get_node!(ef, &dnid).is_cached);
+        }
+        crate::exfat::get_node_mut!(ef, &d3).put();
+
+        ef.flush_node(dirty).unwrap();
+        crate::exfat::get_node_mut!(ef, &referenced).put();
+        assert_eq!(ef.readdir(d2).unwrap().len(), 5);
+        ef.unmount().unwrap();
+    }
+
+    // Whole-tree walks see every node even if they don't fit in --maxmem,
+    // so that no cluster is taken as lost or leaked.
+    #[test]
+    fn test_evict_nodes_walk() {
+        let img = crate::testimg::TestImage::new("evictwalk", 1024);
+        let mut ef = img.mount(&["--noatime"]);
+        for i in 0..10 {
+            ef.mkdir(&format!("/d{i}")).unwrap();
+            ef.mkdir(&format!("/d{i}/s")).unwrap();
+            for path in [format!("/d{i}/f"), format!("/d{i}/s/f")] {
+                let nid = ef.mknod(&path).unwrap();
+                crate::exfat::get_node_mut!(ef, &nid).get();
+                ef.pwrite(nid, &[1; 4096], 0).unwrap();
+                ef.flush_node(nid).unwrap();
+                crate::exfat::get_node_mut!(ef, &nid).put();
+            }
+        }
+        let free = ef.get_free_clusters().unwrap();
+        ef.unmount().unwrap();
+        let bitmap = img.pread(4096, 2 * 4096);
+
+        let mut ef = img.mount(&["--noatime", "--repair", "yes", "--maxmem", "1"]);
+        ef.check_volume().unwrap();
+        assert_eq!(ef.errors_fixed, 0);
+        assert!(ef.rebuild_bitmap().unwrap().is_clean());
+        assert!(ef.check_cross_links().unwrap().is_empty());
+        assert_eq!(ef.get_fragmentation().unwrap().files, 41); // with dirs and root
+        assert!(ef.nmap.len() < 41); // evicted after the walks
+        assert_eq!(ef.get_free_clusters().unwrap(), free);
+        assert!(ef.lookup("/FOUND.000").is_err());
+        ef.unmount().unwrap();
+        assert_eq!(img.pread(4096, 2 * 4096), bitmap);
+    }
+
+    #[test]
+    fn test_entry_ino() {
+        let img = crate::testimg::TestImage::new("ino", 1024);
+        let mut ef = img.mount(&["--noatime", "--ino", "entry"]);
+        ef.mkdir("/d").unwrap();
+        ef.mknod("/d/a").unwrap();
+        ef.mknod("/b").unwrap();
+        let paths = ["/d", "/d/a", "/b"];
+        let stat = |ef: &mut crate::exfat::Exfat, path| {
+            let nid = ef.lookup(path).unwrap();
+            let st = ef.stat(nid).unwrap();
+            crate::exfat::get_node_mut!(ef, &nid).put();
+            (st.st_ino, st.st_gen)
+        };
+        let v = paths.map(|x| stat(&mut ef, x));
+        ef.unmount().unwrap();
+
+        // inos don't depend on nid allocation
+        let mut ef = img.mount(&["--noatime", "--ino", "entry", "--nidalloc", "bitmap"]);
+        assert_eq!(paths.map(|x| stat(&mut ef, x)), v);
+        let b = ef.lookup("/b").unwrap();
+        ef.prune_node(b).unwrap();
+        assert_eq!(paths.map(|x| stat(&mut ef, x)), v);
+        let nid = ef.lookup_ino(v[1].0, v[1].1).unwrap();
+        assert_eq!(crate::exfat::get_node!(ef, &nid).get_name(), "a");
+        crate::exfat::get_node_mut!(ef, &b).put();
+
+        // rename gives the file a new identity
+        ef.rename("/b", "/c").unwrap();
+        assert_ne!(stat(&mut ef, "/c").0, v[2].0);
+        assert!(ef.lookup_ino(v[2].0, v[2].1).is_err());
+        ef.unmount().unwrap();
+
+        // inos which aren't cached are found on disk
+        let mut ef = img.mount(&["--noatime", "--ino", "entry", "--maxmem", "1"]);
+        assert!(!ef.inos.contains_key(&v[1].0));
+        let nid = ef.lookup_ino(v[1].0, v[1].1).unwrap();
+        assert_eq!(crate::exfat::get_node!(ef, &nid).get_name(), "a");
+        assert_eq!(ef.stat(nid).unwrap().st_ino, v[1].0);
+        match ef.lookup_ino(v[1].0, v[1].1 ^ 1) {
+            Err(crate::Error::Errno(nix::errno::Errno::ESTALE)) => (),
+            v => panic!("{v:?}"),
+        }
+        ef.evict_nodes(crate::node::NID_NONE).unwrap();
+        assert!(!ef.inos.contains_key(&v[1].0));
+        let nid = ef.lookup_ino(v[1].0, v[1].1).unwrap();
+        assert_eq!(crate::exfat::get_node!(ef, &nid).get_name(), "a");
+        match ef.lookup_ino(v[2].0, v[2].1) {
+            Err(crate::Error::Errno(nix::errno::Errno::ENOENT)) => (),
+            v => panic!("{v:?}"),
+        }
+        ef.unmount().unwrap();
+    }
+}
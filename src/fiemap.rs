@@ -0,0 +1,128 @@
+// Rust
+// FIEMAP-like extent reporting which doesn't exist in relan/exfat.
+
+pub const EXTENT_FLAG_LAST: u32 = 0x01; // last extent of the node
+pub const EXTENT_FLAG_UNWRITTEN: u32 = 0x02; // beyond valid size, reads as zeroes
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Extent {
+    pub logical: u64,  // offset within the node
+    pub physical: u64, // offset within the device
+    pub length: u64,
+    pub flags: u32,
+}
+
+impl Extent {
+    #[must_use]
+    pub fn is_unwritten(&self) -> bool {
+        (self.flags & EXTENT_FLAG_UNWRITTEN) != 0
+    }
+}
+
+// Convert cluster runs into extents of size bytes, split at valid size.
+fn build_extents<F: Fn(u32) -> u64>(
+    runs: &[(u32, u32)],
+    cluster_size: u64,
+    size: u64,
+    valid_size: u64,
+    c2o: F,
+) -> Vec<Extent> {
+    let mut extents = vec![];
+    let mut logical = 0;
+    for (cluster, n) in runs {
+        if logical >= size {
+            break;
+        }
+        let physical = c2o(*cluster);
+        let end = std::cmp::min(logical + u64::from(*n) * cluster_size, size);
+        if logical < valid_size && valid_size < end {
+            extents.push(Extent {
+                logical,
+                physical,
+                length: valid_size - logical,
+                flags: 0,
+            });
+            extents.push(Extent {
+                logical: valid_size,
+                physical: physical + (valid_size - logical),
+                length: end - valid_size,
+                flags: EXTENT_FLAG_UNWRITTEN,
+            });
+        } else {
+            extents.push(Extent {
+                logical,
+                physical,
+                length: end - logical,
+                flags: if logical >= valid_size {
+                    EXTENT_FLAG_UNWRITTEN
+                } else {
+                    0
+                },
+            });
+        }
+        logical = end;
+    }
+    if let Some(v) = extents.last_mut() {
+        v.flags |= EXTENT_FLAG_LAST;
+    }
+    extents
+}
+
+impl crate::exfat::Exfat {
+    /// # Errors
+    pub fn extents(&mut self, nid: crate::node::Nid) -> crate::Result<Vec<Extent>> {
+        let runs = self.get_cluster_runs(nid)?;
+        let node = crate::exfat::get_node!(self, &nid);
+        Ok(build_extents(
+            &runs,
+            self.get_cluster_size(),
+            node.size,
+            node.valid_size,
+            |x| self.c2o(x),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_build_extents() {
+        let c2o = |x| u64::from(x) * 100;
+        assert!(super::build_extents(&[], 10, 0, 0, c2o).is_empty());
+
+        let v = super::build_extents(&[(2, 3), (10, 2)], 10, 45, 45, c2o);
+        assert_eq!(
+            v,
+            [
+                super::Extent {
+                    logical: 0,
+                    physical: 200,
+                    length: 30,
+                    flags: 0,
+                },
+                super::Extent {
+                    logical: 30,
+                    physical: 1000,
+                    length: 15,
+                    flags: super::EXTENT_FLAG_LAST,
+                },
+            ]
+        );
+
+        let v = super::build_extents(&[(2, 3), (10, 2)], 10, 45, 12, c2o);
+        assert_eq!(v.len(), 3);
+        assert_eq!((v[0].logical, v[0].physical, v[0].length), (0, 200, 12));
+        assert!(!v[0].is_unwritten());
+        assert_eq!((v[1].logical, v[1].physical, v[1].length), (12, 212, 18));
+        assert_eq!(v[1].flags, super::EXTENT_FLAG_UNWRITTEN);
+        assert_eq!((v[2].logical, v[2].physical, v[2].length), (30, 1000, 15));
+        assert_eq!(
+            v[2].flags,
+            super::EXTENT_FLAG_UNWRITTEN | super::EXTENT_FLAG_LAST
+        );
+
+        let v = super::build_extents(&[(2, 3)], 10, 30, 30, c2o);
+        assert_eq!(v.len(), 1);
+        assert_eq!(v[0].flags, super::EXTENT_FLAG_LAST);
+    }
+}
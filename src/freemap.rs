@@ -0,0 +1,191 @@
+// Rust
+// Index of free extents which doesn't exist in relan/exfat.
+// Extents are kept disjoint and non-adjacent, so that each free run is
+// a single extent.
+
+#[derive(Debug, Default)]
+pub(crate) struct FreeMap {
+    by_start: std::collections::BTreeMap<u64, u64>, // start -> length
+    by_len: std::collections::BTreeSet<(u64, u64)>, // (length, start)
+}
+
+impl FreeMap {
+    pub(crate) fn clear(&mut self) {
+        self.by_start.clear();
+        self.by_len.clear();
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.by_start.iter().map(|(k, v)| (*k, *v))
+    }
+
+    fn add(&mut self, start: u64, len: u64) {
+        assert_ne!(len, 0);
+        self.by_start.insert(start, len);
+        self.by_len.insert((len, start));
+    }
+
+    fn del(&mut self, start: u64) -> u64 {
+        let len = self.by_start.remove(&start).unwrap();
+        assert!(self.by_len.remove(&(len, start)));
+        len
+    }
+
+    // Return (start, length) of the extent which contains x.
+    pub(crate) fn get(&self, x: u64) -> Option<(u64, u64)> {
+        let (start, len) = self.by_start.range(..=x).next_back()?;
+        if x < start + len {
+            Some((*start, *len))
+        } else {
+            None
+        }
+    }
+
+    // Return the first free unit at or after x.
+    pub(crate) fn find_next(&self, x: u64) -> Option<u64> {
+        if self.get(x).is_some() {
+            return Some(x);
+        }
+        self.by_start.range(x..).next().map(|(k, _)| *k)
+    }
+
+    // Return (start, length) of the lowest extent of at least n units.
+    pub(crate) fn find_first(&self, n: u64) -> Option<(u64, u64)> {
+        self.by_start
+            .iter()
+            .find(|(_, len)| **len >= n)
+            .map(|(k, v)| (*k, *v))
+    }
+
+    // Return (start, length) of the smallest extent of at least n units,
+    // or the largest extent if there is no such extent.
+    pub(crate) fn find_best(&self, n: u64) -> Option<(u64, u64)> {
+        if let Some((len, start)) = self.by_len.range((n, 0)..).next() {
+            return Some((*start, *len));
+        }
+        // lowest start among the largest ones
+        let (len, _) = self.by_len.last()?;
+        let (len, start) = self.by_len.range((*len, 0)..).next().unwrap();
+        Some((*start, *len))
+    }
+
+    // Mark [start, start+len) free, the range must not be free.
+    pub(crate) fn insert(&mut self, start: u64, len: u64) {
+        if len == 0 {
+            return;
+        }
+        let mut start = start;
+        let mut len = len;
+        if let Some((s, l)) = self.by_start.range(..=start).next_back() {
+            let (s, l) = (*s, *l);
+            assert!(s + l <= start, "{start}+{len} overlaps {s}+{l}");
+            if s + l == start {
+                self.del(s);
+                start = s;
+                len += l;
+            }
+        }
+        if let Some((s, l)) = self.by_start.range(start + 1..).next() {
+            let (s, l) = (*s, *l);
+            assert!(start + len <= s, "{start}+{len} overlaps {s}+{l}");
+            if start + len == s {
+                self.del(s);
+                len += l;
+            }
+        }
+        self.add(start, len);
+    }
+
+    // Drop units at end and after.
+    pub(crate) fn truncate(&mut self, end: u64) {
+        while let Some((start, len)) = self.by_start.last_key_value() {
+            let (start, len) = (*start, *len);
+            if start + len <= end {
+                break;
+            }
+            self.del(start);
+            if start < end {
+                self.add(start, end - start);
+                break;
+            }
+        }
+    }
+
+    // Mark [start, start+len) used, the range must be free.
+    pub(crate) fn remove(&mut self, start: u64, len: u64) {
+        if len == 0 {
+            return;
+        }
+        let Some((s, l)) = self.get(start) else {
+            panic!("{start}+{len} isn't free");
+        };
+        assert!(start + len <= s + l, "{start}+{len} isn't free");
+        self.del(s);
+        if s < start {
+            self.add(s, start - s);
+        }
+        if start + len < s + l {
+            self.add(start + len, s + l - (start + len));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_free_map() {
+        let mut m = super::FreeMap::default();
+        assert_eq!(m.iter().count(), 0);
+        assert_eq!(m.find_next(0), None);
+        assert_eq!(m.find_best(1), None);
+        assert_eq!(m.find_first(1), None);
+
+        m.insert(10, 5);
+        m.insert(20, 2);
+        m.insert(30, 10);
+        assert_eq!(m.iter().count(), 3);
+        assert_eq!(m.get(9), None);
+        assert_eq!(m.get(10), Some((10, 5)));
+        assert_eq!(m.get(14), Some((10, 5)));
+        assert_eq!(m.get(15), None);
+        assert_eq!(m.find_next(0), Some(10));
+        assert_eq!(m.find_next(12), Some(12));
+        assert_eq!(m.find_next(15), Some(20));
+        assert_eq!(m.find_next(40), None);
+        assert_eq!(m.find_best(1), Some((20, 2)));
+        assert_eq!(m.find_best(3), Some((10, 5)));
+        assert_eq!(m.find_best(6), Some((30, 10)));
+        assert_eq!(m.find_best(11), Some((30, 10)));
+        assert_eq!(m.find_first(1), Some((10, 5)));
+        assert_eq!(m.find_first(6), Some((30, 10)));
+        assert_eq!(m.find_first(11), None);
+
+        // merge with both neighbours
+        m.insert(15, 5);
+        assert_eq!(m.iter().collect::<Vec<_>>(), [(10, 12), (30, 10)]);
+        m.insert(22, 8);
+        assert_eq!(m.iter().collect::<Vec<_>>(), [(10, 30)]);
+
+        // split
+        m.remove(10, 1);
+        m.remove(39, 1);
+        m.remove(20, 5);
+        assert_eq!(m.iter().collect::<Vec<_>>(), [(11, 9), (25, 14)]);
+        m.remove(11, 9);
+        assert_eq!(m.iter().collect::<Vec<_>>(), [(25, 14)]);
+
+        // lowest start among the largest ones
+        m.insert(0, 14);
+        assert_eq!(m.find_best(20), Some((0, 14)));
+
+        m.insert(50, 10);
+        m.truncate(55);
+        assert_eq!(m.iter().collect::<Vec<_>>(), [(0, 14), (25, 14), (50, 5)]);
+        m.truncate(30);
+        assert_eq!(m.iter().collect::<Vec<_>>(), [(0, 14), (25, 5)]);
+        m.truncate(14);
+        assert_eq!(m.iter().collect::<Vec<_>>(), [(0, 14)]);
+        m.clear();
+        assert_eq!(m.iter().count(), 0);
+    }
+}
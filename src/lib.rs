@@ -1,11 +1,22 @@
+mod cache;
 pub mod ctl;
+pub mod defrag;
 pub mod device;
 pub mod exfat;
 mod extra;
+pub mod fiemap;
+mod freemap;
 pub mod fs;
+mod lru;
 pub mod node;
 mod option;
+pub mod repair;
+pub mod salvage;
+pub mod space;
+#[cfg(test)]
+mod testimg;
 mod time;
+pub mod undelete;
 pub mod utf;
 pub mod util;
 
@@ -0,0 +1,62 @@
+// Rust
+// LRU order of cached directories which doesn't exist in relan/exfat.
+// Each directory keeps the stamp of its entry, so that entries of
+// directories which have been removed or evicted are detected as stale.
+
+#[derive(Debug, Default)]
+pub(crate) struct NodeLru {
+    tick: u64,
+    order: std::collections::BTreeMap<u64, crate::node::Nid>, // stamp -> nid
+}
+
+impl NodeLru {
+    pub(crate) fn clear(&mut self) {
+        self.order.clear();
+    }
+
+    // Move nid to the most recently used position and return the new stamp.
+    // Stamp 0 means nid isn't in the order yet.
+    pub(crate) fn touch(&mut self, nid: crate::node::Nid, stamp: u64) -> u64 {
+        if stamp != 0 {
+            self.order.remove(&stamp);
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, nid);
+        self.tick
+    }
+
+    pub(crate) fn insert(&mut self, stamp: u64, nid: crate::node::Nid) {
+        assert_ne!(stamp, 0);
+        assert!(self.order.insert(stamp, nid).is_none());
+    }
+
+    // Return (stamp, nid) of the least recently used entry.
+    pub(crate) fn pop(&mut self) -> Option<(u64, crate::node::Nid)> {
+        self.order.pop_first()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_node_lru() {
+        let mut l = super::NodeLru::default();
+        assert_eq!(l.pop(), None);
+
+        let a = l.touch(10, 0);
+        let b = l.touch(20, 0);
+        let c = l.touch(30, 0);
+        assert!(a < b && b < c);
+        let a = l.touch(10, a); // most recently used
+        assert_eq!(l.pop(), Some((b, 20)));
+        l.insert(b, 20); // put back
+        assert_eq!(l.pop(), Some((b, 20)));
+        assert_eq!(l.pop(), Some((c, 30)));
+        assert_eq!(l.pop(), Some((a, 10)));
+        assert_eq!(l.pop(), None);
+
+        l.touch(40, 0);
+        l.clear();
+        assert_eq!(l.pop(), None);
+    }
+}
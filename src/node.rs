@@ -4,11 +4,244 @@ pub(crate) const NID_NONE: Nid = 0;
 pub(crate) const NID_ROOT: Nid = 1;
 pub(crate) const NID_NODE_OFFSET: Nid = 2;
 
+// Logical to physical cluster runs of a non-contiguous node, populated
+// lazily from FAT as the node is accessed.
+#[derive(Debug, Default)]
+pub(crate) struct ExtentMap {
+    extents: Vec<(u32, u32, u32)>, // (logical, physical, count)
+    len: u32,                      // number of logical clusters mapped
+}
+
+impl ExtentMap {
+    pub(crate) fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(crate) fn get(&self, index: u32) -> Option<u32> {
+        if index >= self.len {
+            return None;
+        }
+        let i = self.extents.partition_point(|x| x.0 <= index) - 1;
+        let (logical, physical, _) = self.extents[i];
+        Some(physical + (index - logical))
+    }
+
+    pub(crate) fn push(&mut self, cluster: u32) {
+        match self.extents.last_mut() {
+            Some(x) if x.1 + x.2 == cluster => x.2 += 1,
+            _ => self.extents.push((self.len, cluster, 1)),
+        }
+        self.len += 1;
+    }
+
+    // Drop clusters at index and after.
+    pub(crate) fn truncate(&mut self, index: u32) {
+        if index >= self.len {
+            return;
+        }
+        let i = self.extents.partition_point(|x| x.0 < index);
+        self.extents.truncate(i);
+        if let Some(x) = self.extents.last_mut() {
+            x.2 = std::cmp::min(x.2, index - x.0);
+        }
+        self.len = index;
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.extents.clear();
+        self.len = 0;
+    }
+
+    pub(crate) fn get_heap_usage(&self) -> usize {
+        self.extents.capacity() * std::mem::size_of::<(u32, u32, u32)>()
+    }
+}
+
+// Children of a directory by name hash, built on the first lookup so that
+// upcase table is available, then maintained as children come and go.
+// Boxed, so that files and directories which are never looked up in only
+// pay for a pointer.
+#[derive(Debug, Default)]
+pub(crate) struct NameIndex {
+    #[allow(clippy::box_collection)] // pointer sized when not built
+    map: Option<Box<std::collections::HashMap<u16, Vec<Nid>>>>,
+}
+
+impl NameIndex {
+    pub(crate) fn is_built(&self) -> bool {
+        self.map.is_some()
+    }
+
+    pub(crate) fn build(&mut self, v: &[(u16, Nid)]) {
+        assert!(!self.is_built());
+        self.map = Some(Box::default());
+        for (hash, nid) in v {
+            self.insert(*hash, *nid);
+        }
+    }
+
+    pub(crate) fn get(&self, hash: u16) -> &[Nid] {
+        self.map
+            .as_ref()
+            .and_then(|x| x.get(&hash))
+            .map_or(&[], Vec::as_slice)
+    }
+
+    pub(crate) fn insert(&mut self, hash: u16, nid: Nid) {
+        if let Some(map) = &mut self.map {
+            map.entry(hash).or_default().push(nid);
+        }
+    }
+
+    pub(crate) fn remove(&mut self, hash: u16, nid: Nid) {
+        let Some(map) = &mut self.map else {
+            return;
+        };
+        let v = map.get_mut(&hash).unwrap();
+        let i = v.iter().position(|x| *x == nid).unwrap();
+        v.swap_remove(i);
+        if v.is_empty() {
+            map.remove(&hash);
+        }
+    }
+}
+
+// Nodes by nid. Nodes are stored in fixed size pages with a free list, so
+// that the per-node cost is the node itself plus a small index entry,
+// rather than a hash map slot of the node size which is reallocated and
+// moved as the map grows.
+#[derive(Debug, Default)]
+pub(crate) struct NodeMap {
+    pages: Vec<Box<[Option<Node>]>>,
+    free: Vec<u32>,
+    index: std::collections::HashMap<Nid, u32>, // nid -> slot
+}
+
+const NODE_PAGE_SIZE: usize = 1024; // in nodes
+
+// nid is passed by reference as in HashMap which nmap used to be
+#[allow(clippy::trivially_copy_pass_by_ref)]
+impl NodeMap {
+    fn get_slot(&self, slot: u32) -> Option<&Node> {
+        let slot = usize::try_from(slot).unwrap();
+        self.pages[slot / NODE_PAGE_SIZE][slot % NODE_PAGE_SIZE].as_ref()
+    }
+
+    fn get_slot_mut(&mut self, slot: u32) -> &mut Option<Node> {
+        let slot = usize::try_from(slot).unwrap();
+        &mut self.pages[slot / NODE_PAGE_SIZE][slot % NODE_PAGE_SIZE]
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub(crate) fn contains_key(&self, nid: &Nid) -> bool {
+        self.index.contains_key(nid)
+    }
+
+    pub(crate) fn get(&self, nid: &Nid) -> Option<&Node> {
+        self.get_slot(*self.index.get(nid)?)
+    }
+
+    pub(crate) fn get_mut(&mut self, nid: &Nid) -> Option<&mut Node> {
+        let slot = *self.index.get(nid)?;
+        self.get_slot_mut(slot).as_mut()
+    }
+
+    pub(crate) fn insert(&mut self, nid: Nid, node: Node) -> Option<Node> {
+        if let Some(slot) = self.index.get(&nid) {
+            return self.get_slot_mut(*slot).replace(node);
+        }
+        let slot = self.free.pop().unwrap_or_else(|| self.add_page());
+        *self.get_slot_mut(slot) = Some(node);
+        self.index.insert(nid, slot);
+        None
+    }
+
+    // Return the first slot of a new page, the rest become free.
+    fn add_page(&mut self) -> u32 {
+        let n = self.pages.len() * NODE_PAGE_SIZE;
+        self.pages.push((0..NODE_PAGE_SIZE).map(|_| None).collect());
+        self.free.extend(
+            (n + 1..n + NODE_PAGE_SIZE)
+                .rev()
+                .map(|x| u32::try_from(x).unwrap()),
+        );
+        n.try_into().unwrap()
+    }
+
+    pub(crate) fn remove(&mut self, nid: &Nid) -> Option<Node> {
+        let slot = self.index.remove(nid)?;
+        self.free.push(slot);
+        self.get_slot_mut(slot).take()
+    }
+
+    // Approximate memory used by the map and nodes in it, in bytes.
+    pub(crate) fn get_memory_usage(&self) -> usize {
+        let pages = self.pages.len() * NODE_PAGE_SIZE * std::mem::size_of::<Option<Node>>();
+        let free = self.free.capacity() * std::mem::size_of::<u32>();
+        // hashbrown stores a control byte per bucket
+        let index = self.index.capacity() * (std::mem::size_of::<(Nid, u32)>() + 1);
+        let heap = self
+            .index
+            .keys()
+            .map(|x| self.get(x).unwrap().get_heap_usage())
+            .sum::<usize>();
+        pages + free + index + heap
+    }
+
+    // Approximate memory given back by removing a node, once the map is
+    // shrunk.
+    pub(crate) fn get_node_usage(node: &Node) -> usize {
+        std::mem::size_of::<Option<Node>>()
+            + std::mem::size_of::<(Nid, u32)>()
+            + 1
+            + node.get_heap_usage()
+    }
+
+    // Move nodes to the lowest free slots and release pages left empty,
+    // so that memory of removed nodes is given back.
+    pub(crate) fn shrink_to_fit(&mut self) {
+        let n = self.len().div_ceil(NODE_PAGE_SIZE);
+        let limit = u32::try_from(n * NODE_PAGE_SIZE).unwrap();
+        let mut free = self
+            .free
+            .iter()
+            .copied()
+            .filter(|x| *x < limit)
+            .collect::<Vec<_>>();
+        for slot in self.index.values_mut() {
+            if *slot >= limit {
+                let x = free.pop().unwrap();
+                let (a, b) = (usize::try_from(*slot).unwrap(), usize::try_from(x).unwrap());
+                let node = self.pages[a / NODE_PAGE_SIZE][a % NODE_PAGE_SIZE].take();
+                self.pages[b / NODE_PAGE_SIZE][b % NODE_PAGE_SIZE] = node;
+                *slot = x;
+            }
+        }
+        self.pages.truncate(n);
+        self.pages.shrink_to_fit();
+        self.free = free;
+        self.free.shrink_to_fit();
+        self.index.shrink_to_fit();
+    }
+}
+
 #[derive(Debug)]
 pub struct Node {
     pub(crate) references: isize,
     pub(crate) fptr_index: u32,
     pub(crate) fptr_cluster: u32,
+    pub(crate) extents: ExtentMap, // Rust
     pub(crate) entry_offset: u64,
     pub(crate) start_cluster: u32,
     pub(crate) attrib: u16,
@@ -20,11 +253,15 @@ pub struct Node {
     pub(crate) size: u64,
     pub(crate) mtime: u64,
     pub(crate) atime: u64,
-    pub(crate) name: Vec<u16>,
-    strname: String,            // Rust
-    pub(crate) nid: Nid,        // Rust
-    pub(crate) pnid: Nid,       // Rust
-    pub(crate) cnids: Vec<Nid>, // Rust
+    pub(crate) nid: Nid,                                    // Rust
+    pub(crate) pnid: Nid,                                   // Rust
+    pub(crate) cnids: Vec<Nid>,                             // Rust
+    pub(crate) names: NameIndex,                            // Rust
+    pub(crate) slots: Option<Box<crate::freemap::FreeMap>>, // Rust
+    pub(crate) entries: Box<[crate::fs::ExfatEntry]>,       // Rust: on-disk entry set
+    strname: std::cell::OnceCell<Box<str>>,                 // Rust: see get_name
+    pub(crate) lru: u64,                                    // Rust: stamp in NodeLru
+    pub(crate) ino: u64,                                    // Rust: entry set location
 }
 
 impl Node {
@@ -37,6 +274,7 @@ impl Node {
             references: 0,
             fptr_index: 0,
             fptr_cluster: 0,
+            extents: ExtentMap::default(),
             entry_offset: 0,
             start_cluster: 0,
             attrib: 0,
@@ -48,11 +286,15 @@ impl Node {
             size: 0,
             mtime: 0,
             atime: 0,
-            name: vec![],
-            strname: String::new(),
             nid,
             pnid: NID_NONE,
             cnids: vec![],
+            names: NameIndex::default(),
+            slots: None,
+            entries: Box::default(),
+            strname: std::cell::OnceCell::new(),
+            lru: 0,
+            ino: 0,
         }
     }
 
@@ -106,9 +348,70 @@ impl Node {
         self.size
     }
 
+    // Rust
+    // Name is kept in the entry set, and converted on the first call.
+    /// # Panics
     #[must_use]
     pub fn get_name(&self) -> &str {
-        &self.strname
+        self.strname.get_or_init(|| {
+            let name = self.get_name16();
+            let output =
+                crate::utf::utf16_to_utf8(&name, crate::exfat::UTF8_NAME_BUFFER_MAX, name.len())
+                    .unwrap();
+            libfs::string::b2s(&output).unwrap().into_boxed_str()
+        })
+    }
+
+    // Entries are replaced as a whole, so that the converted name is
+    // dropped along with the old name.
+    pub(crate) fn set_entries(&mut self, entries: Box<[crate::fs::ExfatEntry]>) {
+        self.entries = entries;
+        self.strname = std::cell::OnceCell::new();
+    }
+
+    // UTF-16 name without padding, empty for root.
+    pub(crate) fn get_name16(&self) -> Vec<u16> {
+        if self.entries.len() < 2 {
+            return vec![];
+        }
+        let meta2: &crate::fs::ExfatEntryMeta2 = bytemuck::cast_ref(&self.entries[1]);
+        self.entries[2..]
+            .iter()
+            .flat_map(|x| bytemuck::cast_ref::<_, crate::fs::ExfatEntryName>(x).name)
+            .take(meta2.name_length.into())
+            .collect()
+    }
+
+    // Generation of the entry set, so that a file created in the slot of a
+    // deleted one, or renamed in place, doesn't share the old identity.
+    // Made of creation time and name hash which are stable across mounts.
+    // It's only a hint: a file recreated in the same slot within the same
+    // 10ms with a name of the same hash, or whose fields happen to XOR to
+    // the same value, gets the old generation, and a stale handle then
+    // resolves to it.
+    pub(crate) fn get_generation(&self) -> u32 {
+        Self::calc_generation(&self.entries)
+    }
+
+    // Generation of an entry set which may not be cached, see get_generation.
+    pub(crate) fn calc_generation(entries: &[crate::fs::ExfatEntry]) -> u32 {
+        if entries.len() < 2 {
+            return 0;
+        }
+        let meta1: &crate::fs::ExfatEntryMeta1 = bytemuck::cast_ref(&entries[0]);
+        let meta2: &crate::fs::ExfatEntryMeta2 = bytemuck::cast_ref(&entries[1]);
+        let crtime =
+            u32::from(u16::from_le(meta1.crdate)) << 16 | u32::from(u16::from_le(meta1.crtime));
+        let crtime_cs = u32::from(meta1.crtime_cs) << 8 | u32::from(meta1.crtime_tzo);
+        crtime ^ crtime_cs ^ u32::from(u16::from_le(meta2.name_hash)) << 16
+    }
+
+    // Heap memory owned by the node, in bytes.
+    pub(crate) fn get_heap_usage(&self) -> usize {
+        self.entries.len() * crate::fs::EXFAT_ENTRY_SIZE
+            + self.cnids.capacity() * std::mem::size_of::<Nid>()
+            + self.extents.get_heap_usage()
+            + self.strname.get().map_or(0, |x| x.len())
     }
 
     #[must_use]
@@ -143,31 +446,6 @@ impl Node {
         self.is_contiguous = (meta2.flags & crate::fs::EXFAT_FLAG_CONTIGUOUS) != 0;
     }
 
-    pub(crate) fn init_name(&mut self, entries: &[crate::fs::ExfatEntry], n: usize) {
-        // u16 name
-        assert!(self.name.is_empty());
-        for entry in entries.iter().take(n) {
-            let entry: &crate::fs::ExfatEntryName = bytemuck::cast_ref(entry);
-            self.name.extend_from_slice(&entry.name);
-        }
-        assert_eq!(self.name.len(), crate::fs::EXFAT_ENAME_MAX * n);
-        // string name
-        let output = crate::utf::utf16_to_utf8(
-            &self.name,
-            crate::exfat::UTF8_NAME_BUFFER_MAX,
-            self.name.len(),
-        )
-        .unwrap();
-        assert!(self.strname.is_empty());
-        self.strname = libfs::string::b2s(&output).unwrap();
-    }
-
-    pub(crate) fn update_name(&mut self, entries: &[crate::fs::ExfatEntry], n: usize) {
-        self.name.clear();
-        self.strname.clear();
-        self.init_name(entries, n);
-    }
-
     pub(crate) fn update_atime(&mut self) {
         self.atime = libfs::time::get_current().unwrap();
         self.is_dirty = true;
@@ -185,10 +463,8 @@ impl Node {
         if self.atime == 0 || self.mtime == 0 {
             return false;
         }
-        if self.name.is_empty() || self.name[0] == 0 {
-            return false;
-        }
-        if self.strname.is_empty() {
+        let name = self.get_name16();
+        if name.is_empty() || name[0] == 0 {
             return false;
         }
         if self.nid == NID_NONE {
@@ -228,6 +504,122 @@ mod tests {
         assert_eq!(node.get_name(), "");
     }
 
+    fn make_file(nid: super::Nid, name: &str) -> super::Node {
+        let mut node = super::Node::new(nid);
+        let mut entries = crate::fs::ExfatEntry::bulk_new(3);
+        let meta2: &mut crate::fs::ExfatEntryMeta2 = bytemuck::cast_mut(&mut entries[1]);
+        meta2.name_length = name.len().try_into().unwrap();
+        let ename: &mut crate::fs::ExfatEntryName = bytemuck::cast_mut(&mut entries[2]);
+        for (i, c) in name.encode_utf16().enumerate() {
+            ename.name[i] = c;
+        }
+        node.set_entries(entries.into());
+        node
+    }
+
+    #[test]
+    fn test_node_get_name16() {
+        let node = make_file(10, "IMG_0001.JPG");
+        assert_eq!(node.get_name(), "IMG_0001.JPG");
+        assert_eq!(
+            node.get_name16(),
+            "IMG_0001.JPG".encode_utf16().collect::<Vec<_>>()
+        );
+        assert!(super::Node::new_root().get_name16().is_empty());
+
+        // converted name follows the entry set
+        let mut node = make_file(10, "IMG_0001.JPG");
+        assert_eq!(node.get_name(), "IMG_0001.JPG");
+        node.set_entries(make_file(10, "IMG_0002.JPG").entries);
+        assert_eq!(node.get_name(), "IMG_0002.JPG");
+    }
+
+    #[test]
+    fn test_node_get_generation() {
+        assert_eq!(super::Node::new_root().get_generation(), 0);
+        let mut node = make_file(10, "IMG_0001.JPG");
+        let meta1: &mut crate::fs::ExfatEntryMeta1 = bytemuck::cast_mut(&mut node.entries[0]);
+        meta1.crdate = 0x5a21_u16.to_le();
+        meta1.crtime = 0x6c3e_u16.to_le();
+        let gen = node.get_generation();
+        assert_eq!(gen, 0x5a21_6c3e);
+
+        // created in the same 2 seconds
+        let meta1: &mut crate::fs::ExfatEntryMeta1 = bytemuck::cast_mut(&mut node.entries[0]);
+        meta1.crtime_cs = 50;
+        assert_ne!(node.get_generation(), gen);
+
+        // same name in the same slot at the same time
+        let meta1: &mut crate::fs::ExfatEntryMeta1 = bytemuck::cast_mut(&mut node.entries[0]);
+        meta1.crtime_cs = 0;
+        assert_eq!(node.get_generation(), gen);
+
+        // renamed
+        let meta2: &mut crate::fs::ExfatEntryMeta2 = bytemuck::cast_mut(&mut node.entries[1]);
+        meta2.name_hash = 0x1234_u16.to_le();
+        assert_ne!(node.get_generation(), gen);
+    }
+
+    #[test]
+    fn test_node_map() {
+        let mut m = super::NodeMap::default();
+        assert!(m.is_empty());
+        assert!(m.get(&10).is_none());
+        assert!(m.remove(&10).is_none());
+
+        let n = u64::try_from(super::NODE_PAGE_SIZE).unwrap() + 10;
+        for nid in 10..10 + n {
+            assert!(m.insert(nid, super::Node::new(nid)).is_none());
+        }
+        assert_eq!(m.len(), usize::try_from(n).unwrap());
+        assert_eq!(m.pages.len(), 2);
+        for nid in 10..10 + n {
+            assert!(m.contains_key(&nid));
+            assert_eq!(m.get(&nid).unwrap().nid, nid);
+        }
+        m.get_mut(&20).unwrap().get();
+        assert_eq!(m.get(&20).unwrap().references, 1);
+
+        // slots are reused
+        assert_eq!(m.remove(&15).unwrap().nid, 15);
+        assert!(!m.contains_key(&15));
+        assert!(m.insert(5, super::Node::new(5)).is_none());
+        assert_eq!(m.get(&5).unwrap().nid, 5);
+        assert_eq!(m.pages.len(), 2);
+        assert!(m.insert(5, super::Node::new(5)).is_some());
+
+        // nodes in the last page are moved, and the page is released
+        let usage = m.get_memory_usage();
+        for nid in 20..40 {
+            m.remove(&nid).unwrap();
+        }
+        m.shrink_to_fit();
+        assert_eq!(m.pages.len(), 1);
+        assert!(m.get_memory_usage() < usage);
+        assert_eq!(m.len(), usize::try_from(n).unwrap() - 20);
+        assert_eq!(m.get(&5).unwrap().nid, 5);
+        for nid in (10..15).chain(16..20).chain(40..10 + n) {
+            assert_eq!(m.get(&nid).unwrap().nid, nid);
+        }
+        assert!(m.insert(20, super::Node::new(20)).is_none());
+        assert_eq!(m.pages.len(), 1);
+    }
+
+    // Memory of a typical file node, i.e. a node with three entries, and its
+    // share of the map.
+    #[test]
+    fn test_node_memory() {
+        let n = 10_240;
+        let mut m = super::NodeMap::default();
+        for nid in 0..n {
+            m.insert(nid, make_file(nid, &format!("IMG_{nid:05}.JPG")));
+        }
+        let size = std::mem::size_of::<super::Node>();
+        let usage = m.get_memory_usage() / usize::try_from(n).unwrap();
+        assert!(size <= 208, "{size} bytes per node");
+        assert!(usage <= 336, "{usage} bytes per node in map");
+    }
+
     #[test]
     fn test_node_update_atime() {
         let mut node = super::Node::new_root();
@@ -239,6 +631,67 @@ mod tests {
         assert!(node.is_dirty);
     }
 
+    #[test]
+    fn test_name_index() {
+        let mut m = super::NameIndex::default();
+        m.insert(1, 10); // ignored until built
+        assert!(m.get(1).is_empty());
+
+        m.build(&[(1, 10), (2, 20)]);
+        assert!(m.is_built());
+        m.insert(1, 11);
+        assert_eq!(m.get(1), [10, 11]);
+        assert_eq!(m.get(2), [20]);
+        assert!(m.get(3).is_empty());
+
+        m.remove(1, 10);
+        assert_eq!(m.get(1), [11]);
+        m.remove(1, 11);
+        m.remove(2, 20);
+        assert!(m.map.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_extent_map() {
+        let mut m = super::ExtentMap::default();
+        assert!(m.is_empty());
+        assert_eq!(m.get(0), None);
+
+        // 10 11 12 20 21 5
+        for c in [10, 11, 12, 20, 21, 5] {
+            m.push(c);
+        }
+        assert_eq!(m.len(), 6);
+        assert_eq!(m.extents.len(), 3);
+        for (i, c) in [10, 11, 12, 20, 21, 5].iter().enumerate() {
+            assert_eq!(m.get(i.try_into().unwrap()), Some(*c));
+        }
+        assert_eq!(m.get(6), None);
+
+        m.truncate(4);
+        assert_eq!(m.len(), 4);
+        assert_eq!(m.extents.len(), 2);
+        assert_eq!(m.get(3), Some(20));
+        assert_eq!(m.get(4), None);
+        m.push(21);
+        assert_eq!(m.extents.len(), 2);
+        assert_eq!(m.get(4), Some(21));
+
+        m.truncate(3);
+        assert_eq!(m.extents.len(), 1);
+        m.truncate(1);
+        assert_eq!(m.get(0), Some(10));
+        assert_eq!(m.get(1), None);
+        m.truncate(0);
+        assert!(m.is_empty());
+        assert!(m.extents.is_empty());
+
+        m.push(7);
+        m.clear();
+        assert!(m.is_empty());
+        assert_eq!(m.get(0), None);
+    }
+
     #[test]
     fn test_node_update_mtime() {
         let mut node = super::Node::new_root();
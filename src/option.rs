@@ -18,6 +18,20 @@ pub(crate) enum NidAllocMode {
     Bitmap,
 }
 
+#[derive(Debug)]
+pub(crate) enum InoMode {
+    Nid,
+    Entry,
+}
+
+#[derive(Debug)]
+pub(crate) enum DirtyMode {
+    Continue,
+    Ro,
+    Fail,
+    Check,
+}
+
 #[derive(Debug)]
 pub(crate) struct Opt {
     pub(crate) mode: OpenMode,
@@ -28,6 +42,10 @@ pub(crate) struct Opt {
     pub(crate) uid: u32,
     pub(crate) gid: u32,
     pub(crate) nidalloc: NidAllocMode,
+    pub(crate) dirty: DirtyMode,
+    pub(crate) rescue: bool,
+    pub(crate) maxmem: usize, // in bytes
+    pub(crate) ino: InoMode,
     pub(crate) debug: bool,
 }
 
@@ -43,6 +61,10 @@ impl Opt {
         gopt.optopt("", "uid", "", "<number>");
         gopt.optopt("", "gid", "", "<number>");
         gopt.optopt("", "nidalloc", "", "<linear|bitmap>");
+        gopt.optopt("", "dirty", "", "<continue|ro|fail|check>");
+        gopt.optflag("", "rescue", "");
+        gopt.optopt("", "maxmem", "", "<bytes>");
+        gopt.optopt("", "ino", "", "<nid|entry>");
         gopt.optflag("h", "help", "");
         gopt.optflag("", "debug", "");
         gopt
@@ -139,6 +161,35 @@ impl Opt {
             },
             None => NidAllocMode::Linear,
         };
+        let dirty = match matches.opt_str("dirty") {
+            Some(v) => match v.as_str() {
+                "continue" => DirtyMode::Continue,
+                "ro" => DirtyMode::Ro,
+                "fail" => DirtyMode::Fail,
+                "check" => DirtyMode::Check,
+                _ => return Err(nix::errno::Errno::EINVAL),
+            },
+            None => DirtyMode::Continue,
+        };
+        let rescue = matches.opt_present("rescue");
+        let maxmem = match matches.opt_str("maxmem") {
+            Some(v) => match v.parse() {
+                Ok(v) => v,
+                Err(e) => {
+                    log::error!("{e}");
+                    return Err(nix::errno::Errno::EINVAL);
+                }
+            },
+            None => 0, // no limit
+        };
+        let ino = match matches.opt_str("ino") {
+            Some(v) => match v.as_str() {
+                "nid" => InoMode::Nid,
+                "entry" => InoMode::Entry,
+                _ => return Err(nix::errno::Errno::EINVAL),
+            },
+            None => InoMode::Nid,
+        };
         let debug = matches.opt_present("debug");
         Ok(Self {
             mode,
@@ -149,6 +200,10 @@ impl Opt {
             uid,
             gid,
             nidalloc,
+            dirty,
+            rescue,
+            maxmem,
+            ino,
             debug,
         })
     }
@@ -307,6 +362,109 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_opt_dirty() {
+        match super::Opt::new(&["--dirty", "continue"]) {
+            Ok(v) => match v.dirty {
+                super::DirtyMode::Continue => (),
+                v => panic!("{v:?}"),
+            },
+            Err(e) => panic!("{e}"),
+        }
+        match super::Opt::new(&["--dirty", "ro"]) {
+            Ok(v) => match v.dirty {
+                super::DirtyMode::Ro => (),
+                v => panic!("{v:?}"),
+            },
+            Err(e) => panic!("{e}"),
+        }
+        match super::Opt::new(&["--dirty", "fail"]) {
+            Ok(v) => match v.dirty {
+                super::DirtyMode::Fail => (),
+                v => panic!("{v:?}"),
+            },
+            Err(e) => panic!("{e}"),
+        }
+        match super::Opt::new(&["--dirty", "check"]) {
+            Ok(v) => match v.dirty {
+                super::DirtyMode::Check => (),
+                v => panic!("{v:?}"),
+            },
+            Err(e) => panic!("{e}"),
+        }
+        match super::Opt::new(&[]) {
+            Ok(v) => match v.dirty {
+                super::DirtyMode::Continue => (),
+                v => panic!("{v:?}"),
+            },
+            Err(e) => panic!("{e}"),
+        }
+        match super::Opt::new(&["--dirty", "xxx"]) {
+            Ok(v) => panic!("{v:?}"),
+            Err(nix::errno::Errno::EINVAL) => (),
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    #[test]
+    fn test_opt_rescue() {
+        match super::Opt::new(&["--rescue"]) {
+            Ok(v) => assert!(v.rescue),
+            Err(e) => panic!("{e}"),
+        }
+        match super::Opt::new(&[]) {
+            Ok(v) => assert!(!v.rescue),
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    #[test]
+    fn test_opt_maxmem() {
+        match super::Opt::new(&["--maxmem", "100000"]) {
+            Ok(v) => assert_eq!(v.maxmem, 100_000),
+            Err(e) => panic!("{e}"),
+        }
+        match super::Opt::new(&[]) {
+            Ok(v) => assert_eq!(v.maxmem, 0),
+            Err(e) => panic!("{e}"),
+        }
+        match super::Opt::new(&["--maxmem", "xxx"]) {
+            Ok(v) => panic!("{v:?}"),
+            Err(nix::errno::Errno::EINVAL) => (),
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    #[test]
+    fn test_opt_ino() {
+        match super::Opt::new(&["--ino", "nid"]) {
+            Ok(v) => match v.ino {
+                super::InoMode::Nid => (),
+                v @ super::InoMode::Entry => panic!("{v:?}"),
+            },
+            Err(e) => panic!("{e}"),
+        }
+        match super::Opt::new(&["--ino", "entry"]) {
+            Ok(v) => match v.ino {
+                super::InoMode::Entry => (),
+                v @ super::InoMode::Nid => panic!("{v:?}"),
+            },
+            Err(e) => panic!("{e}"),
+        }
+        match super::Opt::new(&[]) {
+            Ok(v) => match v.ino {
+                super::InoMode::Nid => (),
+                v @ super::InoMode::Entry => panic!("{v:?}"),
+            },
+            Err(e) => panic!("{e}"),
+        }
+        match super::Opt::new(&["--ino", "xxx"]) {
+            Ok(v) => panic!("{v:?}"),
+            Err(nix::errno::Errno::EINVAL) => (),
+            Err(e) => panic!("{e}"),
+        }
+    }
+
     #[test]
     fn test_opt_help() {
         match super::Opt::new(&["-h"]) {
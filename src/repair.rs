@@ -0,0 +1,782 @@
+// Rust
+// Consistency checks and repairs which don't exist in relan/exfat.
+
+#[derive(Debug, Default)]
+pub struct BitmapReport {
+    pub leaked: Vec<(u32, u32)>, // (first cluster, count) allocated but unused
+    pub missing: Vec<(u32, u32)>, // (first cluster, count) used but not allocated
+}
+
+impl BitmapReport {
+    #[must_use]
+    pub fn is_clean(&self) -> bool {
+        self.leaked.is_empty() && self.missing.is_empty()
+    }
+
+    #[must_use]
+    pub fn get_leaked_clusters(&self) -> u64 {
+        self.leaked.iter().map(|x| u64::from(x.1)).sum()
+    }
+
+    #[must_use]
+    pub fn get_missing_clusters(&self) -> u64 {
+        self.missing.iter().map(|x| u64::from(x.1)).sum()
+    }
+}
+
+#[derive(Debug)]
+pub struct LostChain {
+    pub start_cluster: u32,
+    pub clusters: u32,
+    pub is_contiguous: bool, // no FAT chain
+}
+
+#[derive(Debug)]
+pub struct CrossLink {
+    pub nids: Vec<crate::node::Nid>, // files sharing clusters
+    pub clusters: Vec<(u32, u32)>,   // (first cluster, count) shared
+}
+
+// entry set skipped by --rescue
+#[derive(Debug)]
+pub struct SkippedEntry {
+    pub dnid: crate::node::Nid,
+    pub offset: u64,
+    pub entries: usize,
+    pub errno: nix::errno::Errno,
+}
+
+fn push_cluster(v: &mut Vec<(u32, u32)>, cluster: u32) {
+    match v.last_mut() {
+        Some(x) if x.0 + x.1 == cluster => x.1 += 1,
+        _ => v.push((cluster, 1)),
+    }
+}
+
+fn diff_bitmap(
+    old: &libfs::bitmap::Bitmap,
+    new: &libfs::bitmap::Bitmap,
+    count: u32,
+) -> nix::Result<BitmapReport> {
+    let mut report = BitmapReport::default();
+    for i in 0..count {
+        let a = old.is_set(i.try_into().unwrap())?;
+        let b = new.is_set(i.try_into().unwrap())?;
+        if a != b {
+            let cluster = crate::fs::EXFAT_FIRST_DATA_CLUSTER + i;
+            push_cluster(
+                if a {
+                    &mut report.leaked
+                } else {
+                    &mut report.missing
+                },
+                cluster,
+            );
+        }
+    }
+    Ok(report)
+}
+
+impl crate::exfat::Exfat {
+    // Pending directories are referenced, so that they aren't evicted while
+    // caching the others.
+    pub(crate) fn cache_all(&mut self) -> crate::Result<()> {
+        crate::exfat::get_node_mut!(self, &crate::node::NID_ROOT).get();
+        let mut dnids = vec![crate::node::NID_ROOT];
+        while let Some(dnid) = dnids.pop() {
+            if let Err(e) = self.cache_directory(dnid) {
+                for dnid in std::iter::once(dnid).chain(dnids) {
+                    crate::exfat::get_node_mut!(self, &dnid).put();
+                }
+                return Err(e);
+            }
+            for cnid in &crate::exfat::get_node!(self, &dnid).cnids.clone() {
+                let node = crate::exfat::get_node_mut!(self, cnid);
+                if node.is_directory() {
+                    node.get();
+                    dnids.push(*cnid);
+                }
+            }
+            crate::exfat::get_node_mut!(self, &dnid).put();
+        }
+        Ok(())
+    }
+
+    // Run f with all directories cached. Eviction is held off until f
+    // returns, so that nodes walked by f aren't evicted under it.
+    pub(crate) fn with_all_cached<T, F: FnOnce(&mut Self) -> crate::Result<T>>(
+        &mut self,
+        f: F,
+    ) -> crate::Result<T> {
+        self.evict_held += 1;
+        let ret = self.cache_all().and_then(|()| f(self));
+        self.evict_held -= 1;
+        let v = ret?;
+        self.evict_nodes_if_needed(crate::node::NID_NONE)?;
+        Ok(v)
+    }
+
+    // root directory first, then the rest in depth-first order
+    pub(crate) fn collect_nids(&self) -> Vec<crate::node::Nid> {
+        let mut nids = vec![];
+        let mut stack = vec![crate::node::NID_ROOT];
+        while let Some(nid) = stack.pop() {
+            nids.push(nid);
+            stack.extend(crate::exfat::get_node!(self, &nid).cnids.iter().rev());
+        }
+        nids
+    }
+
+    // Walk clusters of all nodes once, and return a new clusters bitmap
+    // built from them along with clusters used by more than one node.
+    // The bitmap also has clusters bitmap itself and upcase table.
+    // Called within with_all_cached.
+    fn walk_clusters(
+        &mut self,
+    ) -> crate::Result<(libfs::bitmap::Bitmap, std::collections::BTreeSet<u32>)> {
+        let mut chunk = libfs::bitmap::Bitmap::new(crate::util::round_up!(
+            usize::try_from(self.cmap.count).unwrap(),
+            libfs::bitmap::BLOCK_BITS
+        ))?;
+        let mut shared = std::collections::BTreeSet::new();
+        for nid in self.collect_nids() {
+            let runs = self.get_cluster_runs(nid)?;
+            self.mark_clusters(&mut chunk, &runs, Some(&mut shared))?;
+        }
+        // clusters bitmap and upcase table are read as contiguous
+        let runs = [
+            (
+                self.cmap.start_cluster,
+                self.bytes2clusters(self.cmap.size)?,
+            ),
+            (
+                self.upcase_start_cluster,
+                self.bytes2clusters(self.upcase_size)?,
+            ),
+        ];
+        self.mark_clusters(&mut chunk, &runs, None)?;
+        Ok((chunk, shared))
+    }
+
+    fn mark_clusters(
+        &self,
+        chunk: &mut libfs::bitmap::Bitmap,
+        runs: &[(u32, u32)],
+        mut shared: Option<&mut std::collections::BTreeSet<u32>>,
+    ) -> crate::Result<()> {
+        for (cluster, n) in runs {
+            for c in *cluster..*cluster + *n {
+                if self.cluster_invalid(c) {
+                    log::error!("invalid cluster {c:#x} while building bitmap");
+                    return Err(nix::errno::Errno::EIO.into());
+                }
+                let index = (c - crate::fs::EXFAT_FIRST_DATA_CLUSTER)
+                    .try_into()
+                    .unwrap();
+                if !chunk.is_set(index)? {
+                    chunk.set(index)?;
+                } else if let Some(v) = shared.as_mut() {
+                    v.insert(c);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Build a new clusters bitmap from clusters bitmap itself, upcase table
+    // and clusters of all nodes.
+    fn build_bitmap(&mut self) -> crate::Result<libfs::bitmap::Bitmap> {
+        self.with_all_cached(|ef| Ok(ef.walk_clusters()?.0))
+    }
+
+    fn log_bitmap_report(report: &BitmapReport) {
+        for (cluster, n) in &report.leaked {
+            log::error!(
+                "clusters {:#x}-{:#x} are allocated but not used",
+                cluster,
+                cluster + (n - 1)
+            );
+        }
+        for (cluster, n) in &report.missing {
+            log::error!(
+                "clusters {:#x}-{:#x} are used but not allocated",
+                cluster,
+                cluster + (n - 1)
+            );
+        }
+    }
+
+    // Clusters of entry sets skipped by --rescue, and of everything under
+    // skipped directories, aren't walked, so they would be taken as unused.
+    fn can_repair(&self) -> bool {
+        if self.skipped.is_empty() {
+            return true;
+        }
+        log::error!(
+            "{} entry sets are skipped, unable to repair",
+            self.skipped.len()
+        );
+        false
+    }
+
+    fn commit_bitmap(&mut self, chunk: libfs::bitmap::Bitmap) -> crate::Result<()> {
+        if self.ro != 0 {
+            log::error!("unable to write clusters bitmap to read-only FS");
+            return Err(nix::errno::Errno::EROFS.into());
+        }
+        self.cmap.set_chunk(chunk);
+        self.flush()
+    }
+
+    /// # Errors
+    pub fn check_bitmap(&mut self) -> crate::Result<BitmapReport> {
+        let chunk = self.build_bitmap()?;
+        self.check_bitmap_with(chunk)
+    }
+
+    fn check_bitmap_with(&mut self, chunk: libfs::bitmap::Bitmap) -> crate::Result<BitmapReport> {
+        let report = diff_bitmap(&self.cmap.chunk, &chunk, self.cmap.count)?;
+        if !report.is_clean() {
+            Self::log_bitmap_report(&report);
+            if self.ro == 0 && self.can_repair() && self.ask_to_fix() {
+                self.commit_bitmap(chunk)?;
+                self.count_errors_fixed();
+            }
+        }
+        Ok(report)
+    }
+
+    /// # Errors
+    pub fn rebuild_bitmap(&mut self) -> crate::Result<BitmapReport> {
+        let chunk = self.build_bitmap()?;
+        if !self.can_repair() {
+            return Err(nix::errno::Errno::EIO.into());
+        }
+        let report = diff_bitmap(&self.cmap.chunk, &chunk, self.cmap.count)?;
+        Self::log_bitmap_report(&report);
+        self.commit_bitmap(chunk)?;
+        Ok(report)
+    }
+
+    pub(crate) fn get_node_path(&self, nid: crate::node::Nid) -> String {
+        let mut v = vec![];
+        let mut nid = nid;
+        while nid != crate::node::NID_ROOT && nid != crate::node::NID_NONE {
+            let node = crate::exfat::get_node!(self, &nid);
+            v.push(node.get_name().to_string());
+            nid = node.pnid;
+        }
+        v.reverse();
+        format!("/{}", v.join("/"))
+    }
+
+    // Files sharing clusters are reported by two passes, the first one finds
+    // shared clusters, and the second one finds owners of them.
+    /// # Errors
+    /// # Panics
+    pub fn check_cross_links(&mut self) -> crate::Result<Vec<CrossLink>> {
+        self.with_all_cached(|ef| {
+            let (_, shared) = ef.walk_clusters()?;
+            ef.get_cross_links(&shared)
+        })
+    }
+
+    fn get_cross_links(
+        &mut self,
+        shared: &std::collections::BTreeSet<u32>,
+    ) -> crate::Result<Vec<CrossLink>> {
+        if shared.is_empty() {
+            return Ok(vec![]);
+        }
+        let nids = self.collect_nids();
+        let mut owners = std::collections::BTreeMap::<u32, Vec<crate::node::Nid>>::new();
+        for nid in &nids {
+            for (cluster, n) in self.get_cluster_runs(*nid)? {
+                for c in cluster..cluster + n {
+                    if shared.contains(&c) {
+                        let v = owners.entry(c).or_default();
+                        if !v.contains(nid) {
+                            v.push(*nid);
+                        }
+                    }
+                }
+            }
+        }
+        let mut groups =
+            std::collections::BTreeMap::<Vec<crate::node::Nid>, Vec<(u32, u32)>>::new();
+        for (c, v) in owners {
+            push_cluster(groups.entry(v).or_default(), c);
+        }
+
+        let mut links = vec![];
+        for (nids, clusters) in groups {
+            let paths = nids
+                .iter()
+                .map(|x| format!("'{}'", self.get_node_path(*x)))
+                .collect::<Vec<_>>();
+            for (cluster, n) in &clusters {
+                log::error!(
+                    "{} share clusters {:#x}-{:#x}",
+                    paths.join(", "),
+                    cluster,
+                    cluster + (n - 1)
+                );
+            }
+            links.push(CrossLink { nids, clusters });
+        }
+        Ok(links)
+    }
+
+    // Run all consistency checks, which also repair per repair option.
+    // Lost chains are taken before the clusters bitmap check frees them.
+    // Clusters are walked once, and clusters of recovered files are added
+    // to the new clusters bitmap afterwards.
+    /// # Errors
+    pub fn check_volume(&mut self) -> crate::Result<()> {
+        log::info!("checking volume");
+        self.with_all_cached(|ef| {
+            let (mut chunk, shared) = ef.walk_clusters()?;
+            ef.get_cross_links(&shared)?;
+            let chains = ef.get_lost_chains(&chunk)?;
+            if !chains.is_empty() {
+                log::error!("{} lost cluster chains found", chains.len());
+                if ef.ro == 0 && ef.can_repair() && ef.ask_to_fix() {
+                    for nid in ef.recover_chains(&chains, false)? {
+                        let runs = ef.get_cluster_runs(nid)?;
+                        ef.mark_clusters(&mut chunk, &runs, None)?;
+                    }
+                    ef.count_errors_fixed();
+                }
+            }
+            ef.check_bitmap_with(chunk)?;
+            Ok(())
+        })
+    }
+
+    // Skip a bad entry set along with its secondary entries, and return
+    // offset of the next entry.
+    pub(crate) fn skip_entry_set(
+        &mut self,
+        dnid: crate::node::Nid,
+        offset: u64,
+        e: &crate::Error,
+    ) -> crate::Result<u64> {
+        let mut next = offset + crate::fs::EXFAT_ENTRY_SIZE_U64;
+        loop {
+            match self.read_entries(dnid, 1, next) {
+                Ok(v) if (v[0].typ & crate::fs::EXFAT_ENTRY_CONTINUED) != 0 => {
+                    next += crate::fs::EXFAT_ENTRY_SIZE_U64;
+                }
+                Ok(_) | Err(crate::Error::Errno(nix::errno::Errno::ENOENT)) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        let entries = ((next - offset) / crate::fs::EXFAT_ENTRY_SIZE_U64)
+            .try_into()
+            .unwrap();
+        log::warn!(
+            "skipping {} entries at {:#x} in '{}': {}",
+            entries,
+            offset,
+            self.get_node_path(dnid),
+            e
+        );
+        if !self
+            .skipped
+            .iter()
+            .any(|x| x.dnid == dnid && x.offset == offset)
+        {
+            self.skipped.push(SkippedEntry {
+                dnid,
+                offset,
+                entries,
+                errno: match e {
+                    crate::Error::Errno(v) => *v,
+                    crate::Error::Error(v) => v
+                        .raw_os_error()
+                        .map_or(nix::errno::Errno::EIO, nix::errno::Errno::from_raw),
+                },
+            });
+        }
+        Ok(next)
+    }
+
+    #[must_use]
+    pub fn get_skipped_entries(&self) -> &[SkippedEntry] {
+        &self.skipped
+    }
+
+    // Allocated but unused clusters are grouped into chains by following
+    // FAT. Clusters with no FAT entry are grouped into contiguous chains.
+    /// # Errors
+    /// # Panics
+    pub fn find_lost_chains(&mut self) -> crate::Result<Vec<LostChain>> {
+        let chunk = self.build_bitmap()?;
+        self.get_lost_chains(&chunk)
+    }
+
+    fn get_lost_chains(&mut self, chunk: &libfs::bitmap::Bitmap) -> crate::Result<Vec<LostChain>> {
+        let mut lost = std::collections::BTreeMap::new(); // cluster -> next
+        for i in 0..self.cmap.count {
+            let index = i.try_into().unwrap();
+            if self.cmap.chunk.is_set(index)? && !chunk.is_set(index)? {
+                let cluster = crate::fs::EXFAT_FIRST_DATA_CLUSTER + i;
+                lost.insert(cluster, self.get_fat_entry(cluster));
+            }
+        }
+
+        let targets = lost
+            .values()
+            .filter(|x| lost.contains_key(x))
+            .copied()
+            .collect::<std::collections::HashSet<_>>();
+        let heads = lost.keys().filter(|x| !targets.contains(x)).copied();
+        // clusters unreachable from heads (i.e. cycles) are taken last
+        let rest = lost.keys().copied().collect::<Vec<_>>();
+        let mut visited = std::collections::HashSet::new();
+        let mut chains = vec![];
+        for cluster in heads.chain(rest) {
+            if visited.contains(&cluster) {
+                continue;
+            }
+            let mut n = 0;
+            let is_contiguous = lost[&cluster] == crate::fs::EXFAT_CLUSTER_FREE;
+            if is_contiguous {
+                while lost.get(&(cluster + n)) == Some(&crate::fs::EXFAT_CLUSTER_FREE)
+                    && visited.insert(cluster + n)
+                {
+                    n += 1;
+                }
+            } else {
+                let mut c = cluster;
+                loop {
+                    visited.insert(c);
+                    n += 1;
+                    c = lost[&c];
+                    if c == crate::fs::EXFAT_CLUSTER_FREE
+                        || !lost.contains_key(&c)
+                        || visited.contains(&c)
+                    {
+                        break;
+                    }
+                }
+            }
+            log::info!(
+                "lost chain of {} clusters starting at {:#x}{}",
+                n,
+                cluster,
+                if is_contiguous { " (contiguous)" } else { "" }
+            );
+            chains.push(LostChain {
+                start_cluster: cluster,
+                clusters: n,
+                is_contiguous,
+            });
+        }
+        Ok(chains)
+    }
+
+    fn free_lost_chain(&mut self, chain: &LostChain) -> crate::Result<()> {
+        let mut cluster = chain.start_cluster;
+        for _ in 0..chain.clusters {
+            let next = if chain.is_contiguous {
+                cluster + 1
+            } else {
+                self.get_fat_entry(cluster)
+            };
+            self.set_next_cluster(chain.is_contiguous, cluster, crate::fs::EXFAT_CLUSTER_FREE)?;
+            self.free_cluster(cluster)?;
+            cluster = next;
+        }
+        Ok(())
+    }
+
+    fn recover_lost_chain(
+        &mut self,
+        dnid: crate::node::Nid,
+        index: usize,
+        chain: &LostChain,
+    ) -> crate::Result<crate::node::Nid> {
+        if !chain.is_contiguous {
+            // chain may have been cut in the middle
+            let mut cluster = chain.start_cluster;
+            for _ in 1..chain.clusters {
+                cluster = self.get_fat_entry(cluster);
+            }
+            self.set_next_cluster(false, cluster, crate::fs::EXFAT_CLUSTER_END)?;
+        }
+        let nid = self.mknod_at(dnid, &format!("FILE{index:04}.CHK"))?;
+        let size = u64::from(chain.clusters) * self.get_cluster_size();
+        let node = crate::exfat::get_node_mut!(self, &nid);
+        node.get();
+        node.start_cluster = chain.start_cluster;
+        node.fptr_index = 0;
+        node.fptr_cluster = node.start_cluster;
+        node.is_contiguous = chain.is_contiguous;
+        node.valid_size = size;
+        node.size = size;
+        node.is_dirty = true;
+        let result = self.flush_node(nid);
+        crate::exfat::get_node_mut!(self, &nid).put();
+        result?;
+        Ok(nid)
+    }
+
+    fn create_found_directory(&mut self) -> crate::Result<crate::node::Nid> {
+        for i in 0..1000 {
+            let name = format!("FOUND.{i:03}");
+            match self.lookup_at(crate::node::NID_ROOT, &name) {
+                Ok(nid) => crate::exfat::get_node_mut!(self, &nid).put(),
+                Err(crate::Error::Errno(nix::errno::Errno::ENOENT)) => {
+                    return self.mkdir_at(crate::node::NID_ROOT, &name);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        log::error!("no FOUND.NNN directory name left");
+        Err(nix::errno::Errno::EEXIST.into())
+    }
+
+    // Lost chains are either reattached as /FOUND.NNN/FILENNNN.CHK files,
+    // or freed.
+    /// # Errors
+    pub fn recover_lost_chains(&mut self, free: bool) -> crate::Result<Vec<LostChain>> {
+        if self.ro != 0 {
+            return Err(nix::errno::Errno::EROFS.into());
+        }
+        let chains = self.find_lost_chains()?;
+        if !chains.is_empty() {
+            if !self.can_repair() {
+                return Err(nix::errno::Errno::EIO.into());
+            }
+            self.recover_chains(&chains, free)?;
+        }
+        Ok(chains)
+    }
+
+    // Return nodes whose clusters have changed, which are root directory,
+    // the new directory and files in it.
+    fn recover_chains(
+        &mut self,
+        chains: &[LostChain],
+        free: bool,
+    ) -> crate::Result<Vec<crate::node::Nid>> {
+        let mut nids = vec![];
+        if free {
+            for chain in chains {
+                self.free_lost_chain(chain)?;
+            }
+        } else {
+            let dnid = self.create_found_directory()?;
+            nids.extend([crate::node::NID_ROOT, dnid]);
+            for (i, chain) in chains.iter().enumerate() {
+                nids.push(self.recover_lost_chain(dnid, i + 1, chain)?);
+            }
+        }
+        self.flush()?;
+        Ok(nids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_diff_bitmap() {
+        let mut a = libfs::bitmap::Bitmap::new(libfs::bitmap::BLOCK_BITS).unwrap();
+        let mut b = libfs::bitmap::Bitmap::new(libfs::bitmap::BLOCK_BITS).unwrap();
+        let report = super::diff_bitmap(&a, &b, 32).unwrap();
+        assert!(report.is_clean());
+
+        for i in [0, 1, 2, 10] {
+            a.set(i).unwrap();
+        }
+        for i in [0, 5, 6, 7, 40] {
+            b.set(i).unwrap();
+        }
+        let report = super::diff_bitmap(&a, &b, 32).unwrap();
+        assert!(!report.is_clean());
+        assert_eq!(report.leaked, [(3, 2), (12, 1)]);
+        assert_eq!(report.missing, [(7, 3)]); // 40 is out of range
+        assert_eq!(report.get_leaked_clusters(), 3);
+        assert_eq!(report.get_missing_clusters(), 3);
+    }
+
+    // Drop entry sets of files by clearing valid bit on disk, so that their
+    // clusters remain allocated but unused.
+    fn drop_entry_sets(img: &crate::testimg::TestImage, sets: &[(u64, usize)]) {
+        for (offset, n) in sets {
+            let size = n * crate::fs::EXFAT_ENTRY_SIZE;
+            let mut buf = img.pread(size, *offset);
+            for i in 0..*n {
+                buf[i * crate::fs::EXFAT_ENTRY_SIZE] &= !crate::fs::EXFAT_ENTRY_VALID;
+            }
+            img.pwrite(&buf, *offset);
+        }
+    }
+
+    #[test]
+    fn test_recover_lost_chains() {
+        let img = crate::testimg::TestImage::new("lost", 1024);
+        let mut ef = img.mount(&["--noatime"]);
+        let cluster_size = usize::try_from(ef.get_cluster_size()).unwrap();
+        let a = ef.mknod("/a").unwrap();
+        let b = ef.mknod("/b").unwrap();
+        let c = ef.mknod("/c").unwrap();
+        let mut sets = vec![];
+        for nid in [a, b, c] {
+            crate::exfat::get_node_mut!(ef, &nid).get();
+        }
+        // interleave a and b to fragment a
+        for i in 0..3 {
+            let offset = u64::try_from(i * cluster_size).unwrap();
+            let buf = vec![b'a' + u8::try_from(i).unwrap(); cluster_size];
+            ef.pwrite(a, &buf, offset).unwrap();
+            ef.pwrite(b, &vec![b'b'; cluster_size], offset).unwrap();
+        }
+        ef.pwrite(c, &vec![b'c'; 2 * cluster_size], 0).unwrap();
+        let root = crate::exfat::get_node!(ef, &crate::node::NID_ROOT).start_cluster;
+        for nid in [a, b, c] {
+            ef.flush_node(nid).unwrap();
+            let node = crate::exfat::get_node!(ef, &nid);
+            if nid != b {
+                sets.push((
+                    ef.c2o(root) + node.entry_offset,
+                    1 + usize::from(node.continuations),
+                ));
+            }
+            crate::exfat::get_node_mut!(ef, &nid).put();
+        }
+        assert!(!crate::exfat::get_node!(ef, &a).is_contiguous);
+        assert!(crate::exfat::get_node!(ef, &c).is_contiguous);
+        let a_start = crate::exfat::get_node!(ef, &a).start_cluster;
+        let c_start = crate::exfat::get_node!(ef, &c).start_cluster;
+        ef.unmount().unwrap();
+        drop_entry_sets(&img, &sets);
+
+        let mut ef = img.mount(&["--noatime", "--repair", "yes"]);
+        let chains = ef.find_lost_chains().unwrap();
+        assert_eq!(chains.len(), 2);
+        assert_eq!(chains[0].start_cluster, a_start);
+        assert_eq!(chains[0].clusters, 3);
+        assert!(!chains[0].is_contiguous);
+        assert_eq!(chains[1].start_cluster, c_start);
+        assert_eq!(chains[1].clusters, 2);
+        assert!(chains[1].is_contiguous);
+
+        ef.check_volume().unwrap();
+        assert_eq!(ef.get_errors_fixed(), 1);
+        assert!(ef.find_lost_chains().unwrap().is_empty());
+        assert!(ef.check_bitmap().unwrap().is_clean());
+        ef.unmount().unwrap();
+
+        let mut ef = img.mount(&["--noatime"]);
+        assert!(ef.check_bitmap().unwrap().is_clean());
+        for (f, data) in [
+            ("/FOUND.000/FILE0001.CHK", [b'a', b'b', b'c']),
+            ("/FOUND.000/FILE0002.CHK", [b'c'; 3]),
+        ] {
+            let nid = ef.lookup(f).unwrap();
+            let buf = ef.read_all(nid).unwrap();
+            let n = buf.len() / cluster_size;
+            for (i, x) in buf.chunks(cluster_size).enumerate() {
+                assert!(x.iter().all(|x| *x == data[i]), "{f}");
+            }
+            assert_eq!(n, if f.ends_with("1.CHK") { 3 } else { 2 });
+            crate::exfat::get_node_mut!(ef, &nid).put();
+        }
+        ef.unmount().unwrap();
+    }
+
+    #[test]
+    fn test_rescue_skipped_entries() {
+        let img = crate::testimg::TestImage::new("rescue", 1024);
+        let mut ef = img.mount(&["--noatime"]);
+        let dnid = ef.mkdir("/d").unwrap();
+        for f in ["x", "y", "z"] {
+            ef.mknod_at(dnid, f).unwrap();
+        }
+        let y = ef.lookup("/d/y").unwrap();
+        let node = crate::exfat::get_node!(ef, &y);
+        let n = 1 + usize::from(node.continuations);
+        let entry_offset = node.entry_offset;
+        crate::exfat::get_node_mut!(ef, &y).put();
+        let cluster = ef.get_cluster_runs(dnid).unwrap()[0].0;
+        let offset = ef.c2o(cluster) + entry_offset;
+        ef.unmount().unwrap();
+
+        // break checksum of y
+        let mut buf = img.pread(n * crate::fs::EXFAT_ENTRY_SIZE, offset);
+        buf[2] ^= 1;
+        img.pwrite(&buf, offset);
+
+        // whole directory fails without --rescue
+        let mut ef = img.mount(&["--noatime"]);
+        assert!(ef.lookup("/d/x").is_err());
+        ef.unmount().unwrap();
+
+        let mut ef = img.mount(&["--noatime", "--rescue"]);
+        let dnid = ef.lookup("/d").unwrap();
+        let v = ef.readdir(dnid).unwrap();
+        let mut names = v
+            .iter()
+            .map(|x| crate::exfat::get_node!(ef, x).get_name())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["x", "z"]);
+        let skipped = ef.get_skipped_entries();
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].dnid, dnid);
+        assert_eq!(skipped[0].offset, entry_offset);
+        assert_eq!(skipped[0].entries, n);
+
+        // new entries don't take the skipped entry set
+        for i in 0..100 {
+            ef.mknod_at(dnid, &format!("f{i}")).unwrap();
+        }
+        crate::exfat::get_node_mut!(ef, &dnid).put();
+        ef.unmount().unwrap();
+        assert_eq!(img.pread(buf.len(), offset), buf);
+    }
+
+    #[test]
+    fn test_rescue_no_repair() {
+        let img = crate::testimg::TestImage::new("rescuerepair", 1024);
+        let mut ef = img.mount(&["--noatime"]);
+        let dnid = ef.mkdir("/d").unwrap();
+        let y = ef.mknod_at(dnid, "y").unwrap();
+        crate::exfat::get_node_mut!(ef, &y).get();
+        crate::testimg::write_clusters(&mut ef, &[y], 2);
+        ef.flush_node(y).unwrap();
+        crate::exfat::get_node_mut!(ef, &y).put();
+        let node = crate::exfat::get_node!(ef, &y);
+        let n = 1 + usize::from(node.continuations);
+        let entry_offset = node.entry_offset;
+        let runs = ef.get_cluster_runs(y).unwrap();
+        let cluster = ef.get_cluster_runs(dnid).unwrap()[0].0;
+        let offset = ef.c2o(cluster) + entry_offset;
+        ef.unmount().unwrap();
+
+        // too few continuations in y, which can't be fixed
+        let mut buf = img.pread(n * crate::fs::EXFAT_ENTRY_SIZE, offset);
+        buf[1] = 1;
+        img.pwrite(&buf, offset);
+
+        // clusters of y look lost, but they are left alone
+        let mut ef = img.mount(&["--noatime", "--rescue", "--repair", "yes"]);
+        let free = ef.get_free_clusters().unwrap();
+        ef.check_volume().unwrap();
+        assert_eq!(ef.get_skipped_entries().len(), 1);
+        assert_eq!(ef.get_errors_fixed(), 0);
+        assert!(ef.rebuild_bitmap().is_err());
+        assert!(ef.recover_lost_chains(false).is_err());
+        assert_eq!(ef.get_free_clusters().unwrap(), free);
+        for (cluster, _) in runs {
+            assert!(ef
+                .is_cluster_allocated(usize::try_from(cluster - 2).unwrap())
+                .unwrap());
+        }
+        assert!(ef.lookup("/FOUND.000").is_err());
+        ef.unmount().unwrap();
+        assert_eq!(img.pread(buf.len(), offset), buf);
+    }
+}
@@ -0,0 +1,228 @@
+// Rust
+// Salvage reads which don't exist in relan/exfat.
+
+#[derive(Debug, Default)]
+pub struct SalvageReport {
+    pub recovered: Vec<(u64, u64)>, // (offset, length) read via cluster chain
+    pub guessed: Vec<(u64, u64)>,   // (offset, length) read via guessed clusters
+    pub synthesized: Vec<(u64, u64)>, // (offset, length) zero-filled
+}
+
+impl SalvageReport {
+    #[must_use]
+    pub fn is_complete(&self) -> bool {
+        self.guessed.is_empty() && self.synthesized.is_empty()
+    }
+}
+
+fn push_range(v: &mut Vec<(u64, u64)>, offset: u64, length: u64) {
+    match v.last_mut() {
+        Some(x) if x.0 + x.1 == offset => x.1 += length,
+        _ => v.push((offset, length)),
+    }
+}
+
+impl crate::exfat::Exfat {
+    // Return cluster at index, or None once the chain is broken.
+    // The chain is walked from the first cluster so that it doesn't depend
+    // on file pointer of the node.
+    fn salvage_cluster(
+        &mut self,
+        nid: crate::node::Nid,
+        walk: &mut Option<(u32, u32, crate::util::CycleDetector)>,
+        index: u32,
+    ) -> Option<u32> {
+        let node = crate::exfat::get_node!(self, &nid);
+        if node.is_contiguous {
+            let cluster = node.start_cluster.checked_add(index)?;
+            return if self.cluster_invalid(cluster) {
+                None
+            } else {
+                Some(cluster)
+            };
+        }
+        let (mut i, mut cluster, mut detector) = walk.take()?;
+        while i < index {
+            cluster = self.next_cluster(nid, cluster);
+            i += 1;
+            if self.cluster_invalid(cluster) || detector.step(cluster) {
+                log::warn!(
+                    "cluster chain of '{}' is broken at {}",
+                    crate::exfat::get_node!(self, &nid).get_name(),
+                    i
+                );
+                return None;
+            }
+        }
+        *walk = Some((i, cluster, detector));
+        Some(cluster)
+    }
+
+    // Read like pread, but unreadable or invalid clusters are zero-filled
+    // instead of failing. If guess_contiguous is set, clusters after a broken
+    // chain are guessed as contiguous from the first cluster.
+    /// # Errors
+    /// # Panics
+    pub fn pread_salvage(
+        &mut self,
+        nid: crate::node::Nid,
+        buf: &mut [u8],
+        offset: u64,
+        guess_contiguous: bool,
+    ) -> crate::Result<(u64, SalvageReport)> {
+        let mut report = SalvageReport::default();
+        let node = crate::exfat::get_node!(self, &nid);
+        let node_size = node.size;
+        let node_valid_size = node.valid_size;
+        let start_cluster = node.start_cluster;
+        if offset >= node_size || buf.is_empty() {
+            return Ok((0, report));
+        }
+        let size = std::cmp::min(u64::try_from(buf.len()).unwrap(), node_size - offset);
+
+        let cluster_size = self.get_cluster_size();
+        let mut walk = if self.cluster_invalid(start_cluster) {
+            None
+        } else {
+            Some((
+                0,
+                start_cluster,
+                crate::util::CycleDetector::new(start_cluster),
+            ))
+        };
+        let mut pos = offset;
+        while pos < offset + size {
+            let index = u32::try_from(pos / cluster_size).unwrap();
+            let loffset = pos % cluster_size;
+            let lsize = std::cmp::min(cluster_size - loffset, offset + size - pos);
+            let beg = usize::try_from(pos - offset).unwrap();
+            let lbuf = &mut buf[beg..beg + usize::try_from(lsize).unwrap()];
+            lbuf.fill(0);
+
+            // no need to read beyond valid size
+            let rsize = std::cmp::min(lsize, node_valid_size.saturating_sub(pos));
+            if rsize == 0 {
+                push_range(&mut report.recovered, pos, lsize);
+                pos += lsize;
+                continue;
+            }
+            let (cluster, guessed) = match self.salvage_cluster(nid, &mut walk, index) {
+                Some(v) => (Some(v), false),
+                None if guess_contiguous => match start_cluster.checked_add(index) {
+                    Some(v) if !self.cluster_invalid(v) => (Some(v), true),
+                    _ => (None, false),
+                },
+                None => (None, false),
+            };
+            let rbuf = &mut lbuf[..usize::try_from(rsize).unwrap()];
+            let ok = match cluster {
+                Some(v) => match self.dev.pread(rbuf, self.c2o(v) + loffset) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        log::warn!("failed to read cluster {v:#x}: {e}");
+                        rbuf.fill(0);
+                        false
+                    }
+                },
+                None => false,
+            };
+            if !ok {
+                push_range(&mut report.synthesized, pos, rsize);
+            } else if guessed {
+                push_range(&mut report.guessed, pos, rsize);
+            } else {
+                push_range(&mut report.recovered, pos, rsize);
+            }
+            if rsize < lsize {
+                push_range(&mut report.recovered, pos + rsize, lsize - rsize);
+            }
+            pos += lsize;
+        }
+        Ok((size, report))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_push_range() {
+        let mut v = vec![];
+        super::push_range(&mut v, 0, 4096);
+        super::push_range(&mut v, 4096, 100);
+        super::push_range(&mut v, 8192, 4096);
+        super::push_range(&mut v, 12288, 1);
+        assert_eq!(v, [(0, 4196), (8192, 4097)]);
+    }
+
+    // Cluster i of buf is filled with data[i].
+    fn is_filled(buf: &[u8], cluster_size: u64, data: &[u8]) -> bool {
+        buf.chunks(usize::try_from(cluster_size).unwrap())
+            .zip(data)
+            .all(|(x, d)| x.iter().all(|x| x == d))
+    }
+
+    #[test]
+    fn test_pread_salvage() {
+        let img = crate::testimg::TestImage::new("salvage", 1024);
+        let mut ef = img.mount(&["--noatime"]);
+        let cluster_size = ef.get_cluster_size();
+        let a = ef.mknod("/a").unwrap();
+        let b = ef.mknod("/b").unwrap();
+        for nid in [a, b] {
+            crate::exfat::get_node_mut!(ef, &nid).get();
+        }
+        crate::testimg::write_clusters(&mut ef, &[a, b], 3);
+        for nid in [a, b] {
+            ef.flush_node(nid).unwrap();
+            crate::exfat::get_node_mut!(ef, &nid).put();
+        }
+        let start_cluster = crate::exfat::get_node!(ef, &a).start_cluster;
+        let fat = u64::from(ef.get_super_block().fat_sector_start) * ef.get_sector_size();
+        ef.unmount().unwrap();
+
+        let mut ef = img.mount(&["--noatime"]);
+        let a = ef.lookup("/a").unwrap();
+        let mut buf = vec![0; usize::try_from(3 * cluster_size).unwrap()];
+        let (n, report) = ef.pread_salvage(a, &mut buf, 0, false).unwrap();
+        assert_eq!(n, 3 * cluster_size);
+        assert!(report.is_complete());
+        assert_eq!(report.recovered, [(0, 3 * cluster_size)]);
+        assert_eq!(buf, ef.read_all(a).unwrap());
+        crate::exfat::get_node_mut!(ef, &a).put();
+        ef.unmount().unwrap();
+
+        // chain of a is broken after the first cluster
+        img.pwrite(
+            &crate::fs::EXFAT_CLUSTER_FREE.to_le_bytes(),
+            fat + u64::from(start_cluster) * 4,
+        );
+        let mut ef = img.mount(&["--noatime"]);
+        let a = ef.lookup("/a").unwrap();
+        let (n, report) = ef.pread_salvage(a, &mut buf, 0, false).unwrap();
+        assert_eq!(n, 3 * cluster_size);
+        assert!(!report.is_complete());
+        assert_eq!(report.recovered, [(0, cluster_size)]);
+        assert_eq!(report.synthesized, [(cluster_size, 2 * cluster_size)]);
+        assert!(is_filled(&buf, cluster_size, &[b'0', 0, 0]));
+
+        // the rest is guessed as contiguous, which is b in this case
+        let (_, report) = ef.pread_salvage(a, &mut buf, 0, true).unwrap();
+        assert_eq!(report.recovered, [(0, cluster_size)]);
+        assert_eq!(report.guessed, [(cluster_size, 2 * cluster_size)]);
+        assert!(report.synthesized.is_empty());
+        assert!(is_filled(&buf, cluster_size, b"001"));
+
+        // partial read within a cluster
+        let (n, report) = ef.pread_salvage(a, &mut buf[..10], 5, false).unwrap();
+        assert_eq!(n, 10);
+        assert_eq!(report.recovered, [(5, 10)]);
+        assert!(
+            ef.pread_salvage(a, &mut buf, 3 * cluster_size, false)
+                .unwrap()
+                .0
+                == 0
+        );
+        crate::exfat::get_node_mut!(ef, &a).put();
+        ef.unmount().unwrap();
+    }
+}
@@ -0,0 +1,115 @@
+// Rust
+// Free space map and statistics which don't exist in relan/exfat.
+
+// Runs of used or free clusters as (first cluster, count).
+#[derive(Debug)]
+pub struct ClusterRuns<'a> {
+    cmap: &'a crate::exfat::ClusterMap,
+    index: usize,
+    used: bool,
+}
+
+impl Iterator for ClusterRuns<'_> {
+    type Item = (u32, u32);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (index, len) = self.cmap.find_run(self.index, self.used)?;
+        self.index = index + len;
+        Some((
+            crate::fs::EXFAT_FIRST_DATA_CLUSTER + u32::try_from(index).unwrap(),
+            u32::try_from(len).unwrap(),
+        ))
+    }
+}
+
+pub const HISTOGRAM_SIZE: usize = 32;
+
+#[derive(Debug, Default)]
+pub struct SpaceReport {
+    pub cluster_size: u64,
+    pub clusters: u32,
+    pub free_clusters: u32,
+    pub largest_free_run: u32, // in clusters
+    // number of runs of 2^i to 2^(i+1)-1 clusters at index i
+    pub free_histogram: [u64; HISTOGRAM_SIZE],
+    pub used_histogram: [u64; HISTOGRAM_SIZE],
+    pub fragmentation: crate::defrag::Fragmentation,
+}
+
+impl SpaceReport {
+    // Whether bytes can be allocated as a contiguous run.
+    #[must_use]
+    pub fn can_allocate_contiguous(&self, bytes: u64) -> bool {
+        crate::util::div_round_up!(bytes, self.cluster_size) <= u64::from(self.largest_free_run)
+    }
+}
+
+fn get_histogram_index(len: u32) -> usize {
+    usize::try_from(len.ilog2()).unwrap()
+}
+
+impl crate::exfat::Exfat {
+    #[must_use]
+    pub fn free_extents(&self) -> ClusterRuns<'_> {
+        ClusterRuns {
+            cmap: &self.cmap,
+            index: 0,
+            used: false,
+        }
+    }
+
+    #[must_use]
+    pub fn used_extents(&self) -> ClusterRuns<'_> {
+        ClusterRuns {
+            cmap: &self.cmap,
+            index: 0,
+            used: true,
+        }
+    }
+
+    /// # Errors
+    pub fn get_space_report(&mut self) -> crate::Result<SpaceReport> {
+        let mut report = SpaceReport {
+            cluster_size: self.get_cluster_size(),
+            clusters: self.cmap.count,
+            free_clusters: self.get_free_clusters()?,
+            ..Default::default()
+        };
+        for (_, n) in self.free_extents() {
+            report.largest_free_run = std::cmp::max(report.largest_free_run, n);
+            report.free_histogram[get_histogram_index(n)] += 1;
+        }
+        for (_, n) in self.used_extents() {
+            report.used_histogram[get_histogram_index(n)] += 1;
+        }
+        report.fragmentation = self.get_fragmentation()?;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_get_histogram_index() {
+        assert_eq!(super::get_histogram_index(1), 0);
+        assert_eq!(super::get_histogram_index(2), 1);
+        assert_eq!(super::get_histogram_index(3), 1);
+        assert_eq!(super::get_histogram_index(4), 2);
+        assert_eq!(
+            super::get_histogram_index(u32::MAX),
+            super::HISTOGRAM_SIZE - 1
+        );
+    }
+
+    #[test]
+    fn test_can_allocate_contiguous() {
+        let report = super::SpaceReport {
+            cluster_size: 4096,
+            largest_free_run: 2,
+            ..Default::default()
+        };
+        assert!(report.can_allocate_contiguous(0));
+        assert!(report.can_allocate_contiguous(8192));
+        assert!(!report.can_allocate_contiguous(8193));
+    }
+}
@@ -0,0 +1,185 @@
+// Rust
+// Minimal exFAT image for tests which doesn't exist in relan/exfat.
+// Bitmap, upcase table and root directory take the first three clusters.
+
+const SECTOR_BITS: u8 = 9;
+const SPC_BITS: u8 = 3;
+const FAT_SECTOR_START: u32 = 128;
+
+#[derive(Debug)]
+pub(crate) struct TestImage {
+    pub(crate) path: String,
+}
+
+impl Drop for TestImage {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl TestImage {
+    pub(crate) fn new(name: &str, cluster_count: u32) -> Self {
+        let sector_size = 1 << SECTOR_BITS;
+        let cluster_size = sector_size << SPC_BITS;
+        let fat_sector_count = (cluster_count + 2) * 4 / sector_size + 1;
+        let cluster_sector_start = (FAT_SECTOR_START + fat_sector_count).next_multiple_of(64);
+        let sector_count = cluster_sector_start + (cluster_count << SPC_BITS);
+        let mut img = vec![0; usize::try_from(sector_count * sector_size).unwrap()];
+        let c2o = |c: u32| {
+            usize::try_from((cluster_sector_start << SECTOR_BITS) + (c - 2) * cluster_size).unwrap()
+        };
+        let put = |img: &mut [u8], offset: usize, buf: &[u8]| {
+            img[offset..offset + buf.len()].copy_from_slice(buf)
+        };
+
+        // super block
+        put(&mut img, 0, &[0xeb, 0x76, 0x90]);
+        put(&mut img, 3, b"EXFAT   ");
+        put(&mut img, 0x48, &u64::from(sector_count).to_le_bytes());
+        put(&mut img, 0x50, &FAT_SECTOR_START.to_le_bytes());
+        put(&mut img, 0x54, &fat_sector_count.to_le_bytes());
+        put(&mut img, 0x58, &cluster_sector_start.to_le_bytes());
+        put(&mut img, 0x5c, &cluster_count.to_le_bytes());
+        put(&mut img, 0x60, &4_u32.to_le_bytes()); // rootdir_cluster
+        put(&mut img, 0x64, &0x1234_5678_u32.to_le_bytes());
+        put(&mut img, 0x68, &[0, 1]); // version 1.0
+        img[0x6c] = SECTOR_BITS;
+        img[0x6d] = SPC_BITS;
+        img[0x6e] = 1; // fat_count
+        img[0x6f] = 0x80; // drive_no
+        put(&mut img, 510, &[0x55, 0xaa]);
+        for i in 1..9 {
+            put(&mut img, i * 512 + 508, &[0, 0, 0x55, 0xaa]);
+        }
+        let checksum = crate::exfat::Exfat::calc_vbr_checksum(&img, sector_size.into());
+        crate::exfat::Exfat::fill_vbr_checksum(&mut img[11 * 512..12 * 512], checksum);
+        img.copy_within(..12 * 512, 12 * 512);
+
+        // FAT, first 2 entries are reserved
+        let fat = usize::try_from(FAT_SECTOR_START * sector_size).unwrap();
+        for (i, x) in [
+            0xffff_fff8,
+            crate::fs::EXFAT_CLUSTER_END,
+            crate::fs::EXFAT_CLUSTER_END,
+            crate::fs::EXFAT_CLUSTER_END,
+            crate::fs::EXFAT_CLUSTER_END,
+        ]
+        .iter()
+        .enumerate()
+        {
+            put(&mut img, fat + i * 4, &u32::to_le_bytes(*x));
+        }
+
+        // clusters bitmap
+        img[c2o(2)] = 0x07;
+
+        // upcase table of ASCII
+        let upcase = (0..128_u16)
+            .map(|x| {
+                if (0x61..=0x7a).contains(&x) {
+                    x - 0x20
+                } else {
+                    x
+                }
+            })
+            .flat_map(u16::to_le_bytes)
+            .collect::<Vec<_>>();
+        put(&mut img, c2o(3), &upcase);
+        let upcase_checksum = upcase.iter().fold(0_u32, |sum, x| {
+            sum.rotate_right(1).wrapping_add(u32::from(*x))
+        });
+
+        // root directory
+        let rootdir = c2o(4);
+        img[rootdir] = crate::fs::EXFAT_ENTRY_LABEL;
+        img[rootdir + 1] = 4;
+        let label = "TEST"
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect::<Vec<_>>();
+        put(&mut img, rootdir + 2, &label);
+        img[rootdir + 32] = crate::fs::EXFAT_ENTRY_BITMAP;
+        put(&mut img, rootdir + 32 + 20, &2_u32.to_le_bytes());
+        put(
+            &mut img,
+            rootdir + 32 + 24,
+            &u64::from(cluster_count.div_ceil(8)).to_le_bytes(),
+        );
+        img[rootdir + 64] = crate::fs::EXFAT_ENTRY_UPCASE;
+        put(&mut img, rootdir + 64 + 4, &upcase_checksum.to_le_bytes());
+        put(&mut img, rootdir + 64 + 20, &3_u32.to_le_bytes());
+        put(
+            &mut img,
+            rootdir + 64 + 24,
+            &u64::try_from(upcase.len()).unwrap().to_le_bytes(),
+        );
+
+        let path = std::env::temp_dir()
+            .join(format!("libexfat-{name}-{}.img", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string();
+        std::fs::write(&path, img).unwrap();
+        Self { path }
+    }
+
+    pub(crate) fn mount(&self, args: &[&str]) -> crate::exfat::Exfat {
+        match crate::exfat::Exfat::mount(&self.path, args) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        }
+    }
+    pub(crate) fn pread(&self, size: usize, offset: u64) -> Vec<u8> {
+        let mut buf = vec![0; size];
+        let f = std::fs::File::open(&self.path).unwrap();
+        std::os::unix::fs::FileExt::read_exact_at(&f, &mut buf, offset).unwrap();
+        buf
+    }
+
+    pub(crate) fn pwrite(&self, buf: &[u8], offset: u64) {
+        let f = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&self.path)
+            .unwrap();
+        std::os::unix::fs::FileExt::write_all_at(&f, buf, offset).unwrap();
+    }
+}
+
+// Append count clusters to each of the files in turn, so that they are
+// fragmented unless there is only one. Cluster i is filled with b'0' + i.
+pub(crate) fn write_clusters(
+    ef: &mut crate::exfat::Exfat,
+    nids: &[crate::node::Nid],
+    count: usize,
+) {
+    let cluster_size = usize::try_from(ef.get_cluster_size()).unwrap();
+    for i in 0..count {
+        let buf = vec![b'0' + u8::try_from(i).unwrap(); cluster_size];
+        for nid in nids {
+            let offset = crate::exfat::get_node!(ef, nid).size;
+            ef.pwrite(*nid, &buf, offset).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_test_image() {
+        let img = super::TestImage::new("image", 1024);
+        let mut ef = img.mount(&["--noatime"]);
+        assert_eq!(ef.get_label(), "TEST");
+        let nid = ef.mknod("/a").unwrap();
+        crate::exfat::get_node_mut!(ef, &nid).get();
+        assert_eq!(ef.pwrite(nid, b"hello", 0).unwrap(), 5);
+        ef.flush_node(nid).unwrap();
+        crate::exfat::get_node_mut!(ef, &nid).put();
+        ef.unmount().unwrap();
+
+        let mut ef = img.mount(&["--noatime"]);
+        let nid = ef.lookup("/a").unwrap();
+        assert_eq!(ef.read_all(nid).unwrap(), b"hello");
+        crate::exfat::get_node_mut!(ef, &nid).put();
+        ef.unmount().unwrap();
+    }
+}
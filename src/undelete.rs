@@ -0,0 +1,438 @@
+// Rust
+// Recovery of deleted directory entries which doesn't exist in relan/exfat.
+
+#[derive(Debug)]
+pub struct DeletedEntry {
+    pub offset: u64, // offset of the entry set within the directory
+    pub name: String,
+    pub attrib: u16,
+    pub size: u64,
+    pub valid_size: u64,
+    pub start_cluster: u32,
+    pub is_contiguous: bool,
+    pub clusters_free: bool, // all clusters are still free in clusters bitmap
+    pub chain_freed: bool,   // FAT chain of a non-contiguous file is gone
+}
+
+impl DeletedEntry {
+    #[must_use]
+    pub fn is_directory(&self) -> bool {
+        (self.attrib & crate::fs::EXFAT_ATTRIB_DIR) != 0
+    }
+}
+
+// Return entries with valid bit restored if they form a deleted entry set
+// with a matching checksum.
+fn restore_entries(entries: &[crate::fs::ExfatEntry]) -> Option<Vec<crate::fs::ExfatEntry>> {
+    if entries.len() < 3
+        || entries[0].typ != crate::fs::EXFAT_ENTRY_FILE & !crate::fs::EXFAT_ENTRY_VALID
+    {
+        return None;
+    }
+    let meta1: &crate::fs::ExfatEntryMeta1 = bytemuck::cast_ref(&entries[0]);
+    let n = 1 + usize::from(meta1.continuations);
+    if n < 3 || n > entries.len() {
+        return None;
+    }
+    // any valid entry means the set has been (partially) reused
+    if entries[..n]
+        .iter()
+        .any(|x| (x.typ & crate::fs::EXFAT_ENTRY_VALID) != 0)
+    {
+        return None;
+    }
+
+    let mut restored = entries[..n].to_vec();
+    for entry in &mut restored {
+        entry.typ |= crate::fs::EXFAT_ENTRY_VALID;
+    }
+    let meta2: &crate::fs::ExfatEntryMeta2 = bytemuck::cast_ref(&restored[1]);
+    if meta2.typ != crate::fs::EXFAT_ENTRY_FILE_INFO
+        || meta2.name_length == 0
+        || (meta2.flags & !(crate::fs::EXFAT_FLAG_ALWAYS1 | crate::fs::EXFAT_FLAG_CONTIGUOUS)) != 0
+    {
+        return None;
+    }
+    let name_entries =
+        crate::util::div_round_up!(usize::from(meta2.name_length), crate::fs::EXFAT_ENAME_MAX);
+    if 2 + name_entries > n {
+        return None;
+    }
+    if restored[2..2 + name_entries]
+        .iter()
+        .any(|x| x.typ != crate::fs::EXFAT_ENTRY_FILE_NAME)
+        || restored[2 + name_entries..]
+            .iter()
+            .any(|x| x.typ < crate::fs::EXFAT_ENTRY_FILE_TAIL)
+    {
+        return None;
+    }
+    if crate::util::calc_checksum(&restored, n) != u16::from_le(meta1.checksum) {
+        return None;
+    }
+    Some(restored)
+}
+
+impl crate::exfat::Exfat {
+    // Collect clusters of a deleted node, or None if they can't be trusted.
+    // A non-contiguous chain is walked by FAT entries, which only remain if
+    // the file was deleted by an implementation which leaves them, e.g.
+    // Windows. This one frees them (as relan/exfat does).
+    fn get_deleted_clusters(
+        &mut self,
+        node: &crate::node::Node,
+    ) -> crate::Result<Option<Vec<u32>>> {
+        let mut clusters = vec![];
+        if node.size == 0 {
+            return Ok(Some(clusters));
+        }
+        let n = self.bytes2clusters(node.size)?;
+        let mut cluster = node.start_cluster;
+        let mut detector = crate::util::CycleDetector::new(cluster);
+        for i in 0..n {
+            if self.cluster_invalid(cluster) || (i != 0 && detector.step(cluster)) {
+                return Ok(None);
+            }
+            clusters.push(cluster);
+            if i + 1 < n {
+                cluster = if node.is_contiguous {
+                    cluster + 1
+                } else {
+                    self.get_fat_entry(cluster)
+                };
+            }
+        }
+        Ok(Some(clusters))
+    }
+
+    fn is_cluster_free(&self, cluster: u32) -> nix::Result<bool> {
+        Ok(!self.cmap.chunk.is_set(
+            (cluster - crate::fs::EXFAT_FIRST_DATA_CLUSTER)
+                .try_into()
+                .unwrap(),
+        )?)
+    }
+
+    fn parse_deleted_entries(
+        &mut self,
+        entries: &[crate::fs::ExfatEntry],
+        offset: u64,
+    ) -> crate::Result<Option<(DeletedEntry, Vec<crate::fs::ExfatEntry>)>> {
+        let Some(restored) = restore_entries(entries) else {
+            return Ok(None);
+        };
+        let meta1: &crate::fs::ExfatEntryMeta1 = bytemuck::cast_ref(&restored[0]);
+        let meta2: &crate::fs::ExfatEntryMeta2 = bytemuck::cast_ref(&restored[1]);
+        let mut node = Self::alloc_node();
+        node.init_meta1(meta1);
+        node.init_meta2(meta2);
+        node.set_entries(restored.as_slice().into());
+        // clusters can't be located once shrink_file has freed the chain
+        let chain_freed = !node.is_contiguous
+            && node.size > self.get_cluster_size()
+            && !self.cluster_invalid(node.start_cluster)
+            && self.get_fat_entry(node.start_cluster) == crate::fs::EXFAT_CLUSTER_FREE;
+        let clusters = if chain_freed {
+            None
+        } else {
+            self.get_deleted_clusters(&node)?
+        };
+        let mut clusters_free = false;
+        if let Some(v) = clusters {
+            clusters_free = true;
+            for cluster in v {
+                if !self.is_cluster_free(cluster)? {
+                    clusters_free = false;
+                    break;
+                }
+            }
+        }
+        Ok(Some((
+            DeletedEntry {
+                offset,
+                name: node.get_name().to_string(),
+                attrib: node.attrib,
+                size: node.size,
+                valid_size: node.valid_size,
+                start_cluster: node.start_cluster,
+                is_contiguous: node.is_contiguous,
+                clusters_free,
+                chain_freed,
+            },
+            restored,
+        )))
+    }
+
+    /// # Errors
+    /// # Panics
+    pub fn scan_deleted(&mut self, dnid: crate::node::Nid) -> crate::Result<Vec<DeletedEntry>> {
+        if !crate::exfat::get_node!(self, &dnid).is_directory() {
+            return Err(nix::errno::Errno::ENOTDIR.into());
+        }
+        let size = crate::exfat::get_node!(self, &dnid).size;
+        let buf = self.preadx(dnid, size, 0)?;
+        let entries = buf
+            .chunks_exact(crate::fs::EXFAT_ENTRY_SIZE)
+            .map(|x| *libfs::cast::align_to::<crate::fs::ExfatEntry>(x))
+            .collect::<Vec<_>>();
+
+        let mut deleted = vec![];
+        let mut i = 0;
+        while i < entries.len() {
+            let offset = crate::fs::EXFAT_ENTRY_SIZE_U64 * u64::try_from(i).unwrap();
+            match self.parse_deleted_entries(&entries[i..], offset)? {
+                Some((v, restored)) => {
+                    i += restored.len();
+                    deleted.push(v);
+                }
+                None => i += 1,
+            }
+        }
+        Ok(deleted)
+    }
+
+    // Clusters are allocated and committed before the entry set becomes
+    // valid again, so that a crash leaks clusters rather than cross-links.
+    /// # Errors
+    /// # Panics
+    pub fn undelete(
+        &mut self,
+        dnid: crate::node::Nid,
+        offset: u64,
+    ) -> crate::Result<crate::node::Nid> {
+        if self.ro != 0 {
+            return Err(nix::errno::Errno::EROFS.into());
+        }
+        if !crate::exfat::get_node!(self, &dnid).is_directory() {
+            return Err(nix::errno::Errno::ENOTDIR.into());
+        }
+        self.cache_directory(dnid)?;
+
+        let entry = &self.read_entries(dnid, 1, offset)?[0];
+        if entry.typ != crate::fs::EXFAT_ENTRY_FILE & !crate::fs::EXFAT_ENTRY_VALID {
+            log::error!("no deleted entry at {offset:#x}");
+            return Err(nix::errno::Errno::ENOENT.into());
+        }
+        let meta1: &crate::fs::ExfatEntryMeta1 = bytemuck::cast_ref(entry);
+        let n = 1 + usize::from(meta1.continuations);
+        let entries = self.read_entries(dnid, n, offset)?;
+        let Some((deleted, restored)) = self.parse_deleted_entries(&entries, offset)? else {
+            log::error!("deleted entry at {offset:#x} can't be restored");
+            return Err(nix::errno::Errno::EIO.into());
+        };
+        if deleted.chain_freed {
+            log::error!("cluster chain of '{}' has been freed", deleted.name);
+            return Err(nix::errno::Errno::EIO.into());
+        }
+        if !deleted.clusters_free {
+            log::error!("clusters of '{}' are no longer free", deleted.name);
+            return Err(nix::errno::Errno::EBUSY.into());
+        }
+        match self.lookup_at(dnid, &deleted.name) {
+            Ok(nid) => {
+                crate::exfat::get_node_mut!(self, &nid).put();
+                log::error!("'{}' already exists", deleted.name);
+                return Err(nix::errno::Errno::EEXIST.into());
+            }
+            Err(crate::Error::Errno(nix::errno::Errno::ENOENT)) => (),
+            Err(e) => return Err(e),
+        }
+
+        let mut node = Self::alloc_node();
+        node.size = deleted.size;
+        node.start_cluster = deleted.start_cluster;
+        node.is_contiguous = deleted.is_contiguous;
+        let Some(clusters) = self.get_deleted_clusters(&node)? else {
+            log::error!("clusters of '{}' can't be located", deleted.name);
+            return Err(nix::errno::Errno::EIO.into());
+        };
+        if !node.is_contiguous && !clusters.is_empty() {
+            // the chain may have been reused past the file size
+            self.set_next_cluster(
+                false,
+                *clusters.last().unwrap(),
+                crate::fs::EXFAT_CLUSTER_END,
+            )?;
+        }
+        for cluster in clusters {
+            self.cmap.set_cluster(cluster)?;
+        }
+        self.flush()?;
+
+        self.write_entries(dnid, &restored, n, offset)?;
+        let nid = self.parse_file_entries(dnid, &restored, n, offset, None)?;
+        let dnode = crate::exfat::get_node_mut!(self, &dnid);
+        dnode.update_mtime();
+        dnode.get();
+        let result = self.flush_node(dnid);
+        crate::exfat::get_node_mut!(self, &dnid).put();
+        result?;
+        log::info!("restored '{}' at {:#x}", deleted.name, offset);
+        Ok(nid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    fn make_entries(name: &str, flags: u8) -> Vec<crate::fs::ExfatEntry> {
+        let mut entries = crate::fs::ExfatEntry::bulk_new(3);
+        let meta1: &mut crate::fs::ExfatEntryMeta1 = bytemuck::cast_mut(&mut entries[0]);
+        meta1.typ = crate::fs::EXFAT_ENTRY_FILE;
+        meta1.continuations = 2;
+        let meta2: &mut crate::fs::ExfatEntryMeta2 = bytemuck::cast_mut(&mut entries[1]);
+        meta2.typ = crate::fs::EXFAT_ENTRY_FILE_INFO;
+        meta2.flags = flags;
+        meta2.name_length = name.len().try_into().unwrap();
+        let ename: &mut crate::fs::ExfatEntryName = bytemuck::cast_mut(&mut entries[2]);
+        ename.typ = crate::fs::EXFAT_ENTRY_FILE_NAME;
+        for (i, c) in name.encode_utf16().enumerate() {
+            ename.name[i] = c;
+        }
+        let checksum = crate::util::calc_checksum(&entries, 3);
+        let meta1: &mut crate::fs::ExfatEntryMeta1 = bytemuck::cast_mut(&mut entries[0]);
+        meta1.checksum = checksum.to_le();
+        entries
+    }
+
+    fn delete_entries(entries: &mut [crate::fs::ExfatEntry]) {
+        for entry in entries {
+            entry.typ &= !crate::fs::EXFAT_ENTRY_VALID;
+        }
+    }
+
+    #[test]
+    fn test_restore_entries() {
+        let entries = make_entries("a.jpg", crate::fs::EXFAT_FLAG_ALWAYS1);
+        assert!(super::restore_entries(&entries).is_none()); // not deleted
+
+        let mut deleted = entries.clone();
+        delete_entries(&mut deleted);
+        let restored = super::restore_entries(&deleted).unwrap();
+        assert_eq!(restored.len(), 3);
+        for (a, b) in restored.iter().zip(&entries) {
+            assert_eq!(a.typ, b.typ);
+            assert_eq!(a.data, b.data);
+        }
+        assert!(super::restore_entries(&deleted[..2]).is_none()); // truncated
+
+        let mut partial = entries.clone();
+        delete_entries(&mut partial[..2]);
+        assert!(super::restore_entries(&partial).is_none()); // reused
+
+        let mut corrupted = deleted.clone();
+        corrupted[2].data[2] ^= 1;
+        assert!(super::restore_entries(&corrupted).is_none()); // checksum
+
+        let mut entries = make_entries("b.jpg", 0x80);
+        delete_entries(&mut entries);
+        assert!(super::restore_entries(&entries).is_none()); // flags
+    }
+
+    fn check_data(ef: &mut crate::exfat::Exfat, nid: crate::node::Nid, count: usize) {
+        let cluster_size = usize::try_from(ef.get_cluster_size()).unwrap();
+        let buf = ef.read_all(nid).unwrap();
+        assert_eq!(buf.len(), count * cluster_size);
+        for (i, x) in buf.chunks(cluster_size).enumerate() {
+            assert!(x.iter().all(|x| *x == b'0' + u8::try_from(i).unwrap()));
+        }
+    }
+
+    // Create /a of 3 clusters, which is fragmented by /b if fragment.
+    fn create_file(img: &crate::testimg::TestImage, fragment: bool) {
+        let mut ef = img.mount(&["--noatime"]);
+        let mut nids = vec![ef.mknod("/a").unwrap()];
+        if fragment {
+            nids.push(ef.mknod("/b").unwrap());
+        }
+        for nid in &nids {
+            crate::exfat::get_node_mut!(ef, nid).get();
+        }
+        crate::testimg::write_clusters(&mut ef, &nids, 3);
+        for nid in &nids {
+            ef.flush_node(*nid).unwrap();
+            crate::exfat::get_node_mut!(ef, nid).put();
+        }
+        assert_eq!(
+            crate::exfat::get_node!(ef, &nids[0]).is_contiguous,
+            !fragment
+        );
+        ef.unmount().unwrap();
+    }
+
+    fn delete_file(img: &crate::testimg::TestImage) {
+        let mut ef = img.mount(&["--noatime"]);
+        let nid = ef.lookup("/a").unwrap();
+        ef.unlink(nid).unwrap();
+        ef.unmount().unwrap();
+    }
+
+    fn undelete_file(img: &crate::testimg::TestImage) -> crate::Result<()> {
+        let mut ef = img.mount(&["--noatime"]);
+        let deleted = ef.scan_deleted(crate::node::NID_ROOT).unwrap();
+        assert_eq!(deleted.len(), 1);
+        assert_eq!(deleted[0].name, "a");
+        let result = ef.undelete(crate::node::NID_ROOT, deleted[0].offset);
+        if let Ok(nid) = result {
+            crate::exfat::get_node_mut!(ef, &nid).get();
+            check_data(&mut ef, nid, 3);
+            crate::exfat::get_node_mut!(ef, &nid).put();
+            assert!(ef.check_bitmap().unwrap().is_clean());
+        }
+        ef.unmount().unwrap();
+        result.map(|_| ())
+    }
+
+    #[test]
+    fn test_undelete_contiguous() {
+        let img = crate::testimg::TestImage::new("undelete-contiguous", 1024);
+        create_file(&img, false);
+        delete_file(&img);
+        let mut ef = img.mount(&["--noatime"]);
+        let deleted = ef.scan_deleted(crate::node::NID_ROOT).unwrap();
+        assert!(deleted[0].is_contiguous);
+        assert!(deleted[0].clusters_free);
+        assert!(!deleted[0].chain_freed);
+        ef.unmount().unwrap();
+        undelete_file(&img).unwrap();
+
+        // restored file is cached after remount
+        let mut ef = img.mount(&["--noatime"]);
+        let nid = ef.lookup("/a").unwrap();
+        check_data(&mut ef, nid, 3);
+        crate::exfat::get_node_mut!(ef, &nid).put();
+        ef.unmount().unwrap();
+    }
+
+    #[test]
+    fn test_undelete_fragmented() {
+        // FAT chain is freed on deletion
+        let img = crate::testimg::TestImage::new("undelete-fragmented", 1024);
+        create_file(&img, true);
+        delete_file(&img);
+        let mut ef = img.mount(&["--noatime"]);
+        let deleted = ef.scan_deleted(crate::node::NID_ROOT).unwrap();
+        assert!(!deleted[0].is_contiguous);
+        assert!(!deleted[0].clusters_free);
+        assert!(deleted[0].chain_freed);
+        ef.unmount().unwrap();
+        match undelete_file(&img) {
+            Ok(()) => panic!("undeleted"),
+            Err(crate::Error::Errno(nix::errno::Errno::EIO)) => (),
+            Err(e) => panic!("{e}"),
+        }
+
+        // FAT chain is left on deletion
+        let img = crate::testimg::TestImage::new("undelete-fragmented-fat", 1024);
+        create_file(&img, true);
+        let ef = img.mount(&["--noatime"]);
+        let sb = ef.get_super_block();
+        let fat = u64::from(sb.fat_sector_start) * ef.get_sector_size();
+        let fat_size =
+            usize::try_from(u64::from(sb.fat_sector_count) * ef.get_sector_size()).unwrap();
+        drop(ef);
+        let buf = img.pread(fat_size, fat);
+        delete_file(&img);
+        img.pwrite(&buf, fat);
+        undelete_file(&img).unwrap();
+    }
+}
@@ -102,8 +102,8 @@ pub(crate) fn calc_name_hash(upcase: &[u16], name: &[u16], length: usize) -> u16
         let c = u16::from_le(*x);
         // convert to upper case
         let c = upcase[usize::from(c)];
-        hash = hash.rotate_right(1) + (c & 0xff);
-        hash = hash.rotate_right(1) + (c >> 8);
+        hash = hash.rotate_right(1).wrapping_add(c & 0xff);
+        hash = hash.rotate_right(1).wrapping_add(c >> 8);
     }
     hash.to_le()
 }
@@ -132,6 +132,38 @@ pub fn humanize_bytes(value: u64) -> (u64, String) {
     (temp, units[i].to_string())
 }
 
+// Brent's cycle detection for walking cluster chains.
+// Feed clusters following the first one, true is returned once a cycle is found.
+#[derive(Debug)]
+pub(crate) struct CycleDetector {
+    tortoise: u32,
+    hare: u32,
+    power: u64,
+    lam: u64,
+}
+
+impl CycleDetector {
+    pub(crate) fn new(cluster: u32) -> Self {
+        Self {
+            tortoise: cluster,
+            hare: cluster,
+            power: 1,
+            lam: 0,
+        }
+    }
+
+    pub(crate) fn step(&mut self, cluster: u32) -> bool {
+        if self.power == self.lam {
+            self.tortoise = self.hare;
+            self.power *= 2;
+            self.lam = 0;
+        }
+        self.hare = cluster;
+        self.lam += 1;
+        self.hare == self.tortoise
+    }
+}
+
 pub(crate) fn read_line() -> std::io::Result<String> {
     let mut s = String::new();
     std::io::stdin().read_line(&mut s)?;
@@ -172,6 +204,33 @@ mod tests {
         assert_eq!(super::round_down!(1025_u32, 1024), 1024);
     }
 
+    #[test]
+    fn test_cycle_detector() {
+        // 2 -> 3 -> ... -> 9 -> END
+        let mut d = super::CycleDetector::new(2);
+        for c in 3..10 {
+            assert!(!d.step(c));
+        }
+
+        // 2 -> 2
+        let mut d = super::CycleDetector::new(2);
+        assert!(d.step(2));
+
+        // 2 -> 3 -> 4 -> 5 -> 6 -> 4
+        let next = |c| if c == 6 { 4 } else { c + 1 };
+        let mut d = super::CycleDetector::new(2);
+        let mut c = 2;
+        let mut n = 0;
+        loop {
+            c = next(c);
+            n += 1;
+            if d.step(c) {
+                break;
+            }
+            assert!(n < 100, "cycle not detected");
+        }
+    }
+
     #[test]
     fn test_humanize_bytes() {
         let (value, unit) = super::humanize_bytes(0);